@@ -0,0 +1,306 @@
+use crate::config::{ApiKeyLocation, Auth, AwsSigV4, Hmac, OAuth2};
+use crate::oauth2;
+use crate::output::Printer;
+use crate::signing;
+use crate::templating::{render_placeholders, Vars};
+use anyhow::Context;
+use base64::Engine as _;
+use reqwest::blocking::{Client, Request, Response};
+use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::StatusCode;
+use rusqlite::Connection;
+
+/// Replace ${var} placeholders in every field of an auth scheme.
+pub fn render(auth: &Auth, vars: &Vars) -> anyhow::Result<Auth> {
+    let r = |s: &str| render_placeholders(s, vars);
+    Ok(match auth {
+        Auth::Basic { username, password } => Auth::Basic {
+            username: r(username)?,
+            password: password.as_deref().map(r).transpose()?,
+        },
+        Auth::Bearer { token } => Auth::Bearer { token: r(token)? },
+        Auth::ApiKey {
+            name,
+            value,
+            location,
+        } => Auth::ApiKey {
+            name: r(name)?,
+            value: r(value)?,
+            location: *location,
+        },
+        Auth::Digest { username, password } => Auth::Digest {
+            username: r(username)?,
+            password: r(password)?,
+        },
+        Auth::OAuth2(cfg) => Auth::OAuth2(OAuth2 {
+            token_url: r(&cfg.token_url)?,
+            client_id: r(&cfg.client_id)?,
+            client_secret: cfg.client_secret.as_deref().map(r).transpose()?,
+            scopes: cfg.scopes.iter().map(|s| r(s)).collect::<Result<_, _>>()?,
+            grant_type: cfg.grant_type,
+            refresh_token: cfg.refresh_token.as_deref().map(r).transpose()?,
+        }),
+        Auth::AwsSigV4(cfg) => Auth::AwsSigV4(AwsSigV4 {
+            access_key: r(&cfg.access_key)?,
+            secret_key: r(&cfg.secret_key)?,
+            session_token: cfg.session_token.as_deref().map(r).transpose()?,
+            region: r(&cfg.region)?,
+            service: r(&cfg.service)?,
+        }),
+        Auth::Hmac(cfg) => Auth::Hmac(Hmac {
+            secret: r(&cfg.secret)?,
+            header: r(&cfg.header)?,
+            prefix: cfg.prefix.as_deref().map(r).transpose()?,
+            timestamp_header: cfg.timestamp_header.as_deref().map(r).transpose()?,
+            ..cfg.clone()
+        }),
+    })
+}
+
+/// Turn schemes that need a round-trip beforehand (OAuth2) into the
+/// credentials actually sent with the request.
+pub fn resolve(
+    conn: &Connection,
+    client: &Client,
+    project: &str,
+    auth: Auth,
+) -> anyhow::Result<Auth> {
+    match auth {
+        Auth::OAuth2(cfg) => Ok(Auth::Bearer {
+            token: oauth2::access_token(conn, client, project, &cfg)?,
+        }),
+        auth => Ok(auth),
+    }
+}
+
+/// Send `request`, authenticated with `auth` (already rendered and resolved).
+/// With `-v`, `printer` shows every request as sent, auth included.
+pub fn send(
+    client: &Client,
+    mut request: Request,
+    auth: Option<&Auth>,
+    printer: &Printer,
+) -> anyhow::Result<Response> {
+    match auth {
+        None => {
+            printer.request(&request);
+            Ok(client.execute(request)?)
+        }
+        Some(Auth::Digest { username, password }) => {
+            send_digest(client, request, username, password, printer)
+        }
+        Some(auth) => {
+            apply(&mut request, auth)?;
+            printer.request(&request);
+            Ok(client.execute(request)?)
+        }
+    }
+}
+
+/// Attach a non challenge-based scheme to the request.
+///
+/// Signing schemes see the request exactly as it is about to be sent.
+fn apply(request: &mut Request, auth: &Auth) -> anyhow::Result<()> {
+    match auth {
+        Auth::Basic { username, password } => {
+            let credentials = format!("{}:{}", username, password.as_deref().unwrap_or(""));
+            let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+            set_header(request, AUTHORIZATION, &format!("Basic {encoded}"))?;
+        }
+        Auth::Bearer { token } => {
+            set_header(request, AUTHORIZATION, &format!("Bearer {token}"))?;
+        }
+        Auth::ApiKey {
+            name,
+            value,
+            location: ApiKeyLocation::Header,
+        } => {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid api key header name `{name}`"))?;
+            set_header(request, name, value)?;
+        }
+        Auth::ApiKey {
+            name,
+            value,
+            location: ApiKeyLocation::Query,
+        } => {
+            request.url_mut().query_pairs_mut().append_pair(name, value);
+        }
+        Auth::Digest { .. } => unreachable!("digest auth is answered in send_digest"),
+        Auth::OAuth2(_) => anyhow::bail!("oauth2 auth must be resolved before sending"),
+        Auth::AwsSigV4(cfg) => signing::sign_aws_v4(request, cfg, chrono::Utc::now())?,
+        Auth::Hmac(cfg) => signing::sign_hmac(request, cfg, chrono::Utc::now())?,
+    }
+    Ok(())
+}
+
+fn set_header(request: &mut Request, name: HeaderName, value: &str) -> anyhow::Result<()> {
+    let value = HeaderValue::from_str(value).context("invalid characters in auth header")?;
+    request.headers_mut().insert(name, value);
+    Ok(())
+}
+
+/// Send the request unauthenticated; if the server answers with a Digest
+/// challenge, compute the response and replay the request once.
+fn send_digest(
+    client: &Client,
+    request: Request,
+    username: &str,
+    password: &str,
+    printer: &Printer,
+) -> anyhow::Result<Response> {
+    let Some(mut replay) = request.try_clone() else {
+        anyhow::bail!("digest auth requires a replayable request body");
+    };
+
+    printer.request(&request);
+    let resp = client.execute(request)?;
+    if resp.status() != StatusCode::UNAUTHORIZED {
+        return Ok(resp);
+    }
+    let Some(challenge) = resp
+        .headers()
+        .get(WWW_AUTHENTICATE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return Ok(resp);
+    };
+
+    let mut prompt = digest_auth::parse(challenge)
+        .map_err(|e| anyhow::anyhow!("invalid digest challenge: {e}"))?;
+
+    let url = replay.url();
+    let uri = match url.query() {
+        Some(q) => format!("{}?{}", url.path(), q),
+        None => url.path().to_string(),
+    };
+    let method = replay.method().as_str().to_string();
+    let body = replay.body().and_then(|b| b.as_bytes()).map(|b| b.to_vec());
+    let context = digest_auth::AuthContext::new_with_method(
+        username,
+        password,
+        uri,
+        body,
+        digest_auth::HttpMethod::from(method),
+    );
+    let answer = prompt
+        .respond(&context)
+        .map_err(|e| anyhow::anyhow!("could not answer digest challenge: {e}"))?;
+
+    set_header(&mut replay, AUTHORIZATION, &answer.to_header_string())?;
+    printer.request(&replay);
+    Ok(client.execute(replay)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+
+    #[test]
+    fn render_replaces_placeholders_in_fields() {
+        let mut vars = Vars::new();
+        vars.insert("token".into(), "abc".into());
+
+        let auth = Auth::Bearer {
+            token: "${token}".into(),
+        };
+        assert_eq!(
+            render(&auth, &vars).unwrap(),
+            Auth::Bearer {
+                token: "abc".into()
+            }
+        );
+    }
+
+    #[test]
+    fn basic_auth_sets_authorization_header() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            // "user:pass" in base64
+            when.method(GET)
+                .path("/private")
+                .header("authorization", "Basic dXNlcjpwYXNz");
+            then.status(200);
+        });
+
+        let client = Client::new();
+        let request = client.get(server.url("/private")).build().unwrap();
+        let auth = Auth::Basic {
+            username: "user".into(),
+            password: Some("pass".into()),
+        };
+        let printer = Printer {
+            verbose: true,
+            ..Default::default()
+        }
+        .buffered();
+        let resp = send(&client, request, Some(&auth), &printer).unwrap();
+
+        assert_eq!(resp.status(), 200);
+        m.assert();
+        // -v shows the header added by the auth
+        assert!(printer
+            .held()
+            .contains("> authorization: Basic dXNlcjpwYXNz\n"));
+    }
+
+    #[test]
+    fn api_key_can_go_in_query() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(GET).path("/items").query_param("key", "k-1");
+            then.status(200);
+        });
+
+        let client = Client::new();
+        let request = client.get(server.url("/items")).build().unwrap();
+        let auth = Auth::ApiKey {
+            name: "key".into(),
+            value: "k-1".into(),
+            location: ApiKeyLocation::Query,
+        };
+        send(&client, request, Some(&auth), &Printer::default()).unwrap();
+
+        m.assert();
+    }
+
+    #[test]
+    fn digest_auth_answers_challenge() {
+        let server = MockServer::start();
+        let authorized = server.mock(|when, then| {
+            when.method(GET)
+                .path("/digest")
+                .header_exists("authorization");
+            then.status(200).body("welcome");
+        });
+        let challenge = server.mock(|when, then| {
+            when.method(GET).path("/digest");
+            then.status(401).header(
+                "WWW-Authenticate",
+                r#"Digest realm="test", qop="auth", nonce="abc", opaque="xyz""#,
+            );
+        });
+
+        let client = Client::new();
+        let request = client.get(server.url("/digest")).build().unwrap();
+        let auth = Auth::Digest {
+            username: "me".into(),
+            password: "secret".into(),
+        };
+        let printer = Printer {
+            verbose: true,
+            ..Default::default()
+        }
+        .buffered();
+        let resp = send(&client, request, Some(&auth), &printer).unwrap();
+
+        assert_eq!(resp.status(), 200);
+        challenge.assert();
+        authorized.assert();
+        // both the challenged request and its answer are shown
+        let held = printer.held();
+        assert_eq!(held.matches("> GET ").count(), 2);
+        assert!(held.contains("> authorization: Digest username=\"me\""));
+    }
+}
@@ -0,0 +1,409 @@
+use crate::auth;
+use crate::client;
+use crate::config::{Auth, ProjectConfig};
+use crate::cookies::Jar;
+use crate::output::Printer;
+use crate::ratelimit::RateLimiter;
+use crate::runner::{render_request, request_builder};
+use crate::templating::Vars;
+use anyhow::Context;
+use reqwest::blocking::{Client, Request};
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Requests sent when neither `--requests` nor `--duration` is given.
+const DEFAULT_REQUESTS: usize = 100;
+/// Buckets of the latency histogram.
+const HISTOGRAM_BUCKETS: usize = 10;
+/// Width of the longest histogram bar.
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// Options of `qwest bench`.
+#[derive(Debug, Clone, Default)]
+pub struct BenchOptions {
+    /// Requests to send, unlimited within `duration` if unset
+    pub requests: Option<usize>,
+    /// Requests in flight at once
+    pub concurrency: usize,
+    /// Stop sending after this long
+    pub duration: Option<Duration>,
+    /// Requests per second, across all workers
+    pub rate: Option<f64>,
+}
+
+/// One request sent by a bench.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Since the start of the bench
+    pub started: Duration,
+    /// Until the whole body was received
+    pub latency: Duration,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+impl Sample {
+    fn failed(&self) -> bool {
+        self.status.is_none_or(|s| s >= 400)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BenchReport {
+    pub samples: Vec<Sample>,
+    pub elapsed: Duration,
+}
+
+/// Send a request of the project over and over, rendered once with `vars`.
+///
+/// Scripts don't run; the auth is resolved once, so OAuth2 tokens are only
+/// fetched before the bench starts. The project `rate_limit` holds on top
+/// of `--rate`.
+pub fn run(
+    conn: &Connection,
+    cfg: &ProjectConfig,
+    route: &str,
+    vars: &Vars,
+    opts: &BenchOptions,
+) -> anyhow::Result<BenchReport> {
+    let api = &cfg.api;
+    let Some(request) = cfg.requests.iter().find(|r| r.name == route) else {
+        if api.scenarios.contains_key(route) {
+            anyhow::bail!("`{route}` is a scenario, bench runs a single request");
+        }
+        anyhow::bail!("unknown request `{route}`");
+    };
+
+    let http = match &request.http {
+        Some(http) => api.http.merged(http),
+        None => api.http.clone(),
+    };
+    let client = client::build(&http, &Jar::default())?;
+    let template = request_builder(&client, render_request(api, request, vars, true)?)?.build()?;
+    let auth = match request.auth.as_ref().or(api.auth.as_ref()) {
+        Some(a) => Some(auth::resolve(
+            conn,
+            &client,
+            &api.name,
+            auth::render(a, vars)?,
+        )?),
+        None => None,
+    };
+
+    let limit = match (opts.requests, opts.duration) {
+        (None, None) => Some(DEFAULT_REQUESTS),
+        (requests, _) => requests,
+    };
+    // every worker sends its own copy of the request
+    let templates = (0..opts.concurrency.max(1))
+        .map(|_| {
+            template
+                .try_clone()
+                .context("the request body can't be replayed")
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let next = AtomicUsize::new(0);
+    let samples = Mutex::new(Vec::new());
+    let limiter = RateLimiter::new(api.rate_limit);
+    let start = Instant::now();
+
+    std::thread::scope(|scope| {
+        for template in templates {
+            let (client, auth, next, samples) = (&client, auth.as_ref(), &next, &samples);
+            let limiter = &limiter;
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if limit.is_some_and(|n| i >= n) {
+                    break;
+                }
+                if let Some(rate) = opts.rate {
+                    let due = Duration::from_secs_f64(i as f64 / rate);
+                    if let Some(wait) = due.checked_sub(start.elapsed()) {
+                        std::thread::sleep(wait);
+                    }
+                }
+                limiter.wait();
+                if opts.duration.is_some_and(|d| start.elapsed() >= d) {
+                    break;
+                }
+
+                let sample = send(client, &template, auth, start);
+                samples.lock().unwrap().push(sample);
+            });
+        }
+    });
+
+    let mut samples = samples.into_inner().unwrap();
+    samples.sort_by_key(|s| s.started);
+    Ok(BenchReport {
+        samples,
+        elapsed: start.elapsed(),
+    })
+}
+
+fn send(client: &Client, template: &Request, auth: Option<&Auth>, start: Instant) -> Sample {
+    let started = start.elapsed();
+    let sent = Instant::now();
+    let result = template
+        .try_clone()
+        .context("the request body can't be replayed")
+        .and_then(|request| auth::send(client, request, auth, &Printer::default()))
+        .and_then(|response| {
+            let status = response.status().as_u16();
+            response.bytes()?;
+            Ok(status)
+        });
+
+    let (status, error) = match result {
+        Ok(status) => (Some(status), None),
+        Err(e) => (None, Some(format!("{e:#}"))),
+    };
+    Sample {
+        started,
+        latency: sent.elapsed(),
+        status,
+        error,
+    }
+}
+
+impl BenchReport {
+    pub fn throughput(&self) -> f64 {
+        self.samples.len() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Requests without a response or answered with a 4xx/5xx status.
+    pub fn error_count(&self) -> usize {
+        self.samples.iter().filter(|s| s.failed()).count()
+    }
+
+    /// Number of requests per status, `None` for the ones without a response.
+    pub fn statuses(&self) -> BTreeMap<Option<u16>, usize> {
+        let mut statuses = BTreeMap::new();
+        for sample in &self.samples {
+            *statuses.entry(sample.status).or_default() += 1;
+        }
+        statuses
+    }
+
+    /// Latency under which `p` percent of the requests completed (nearest rank).
+    pub fn percentile(&self, p: f64) -> Duration {
+        let mut latencies: Vec<_> = self.samples.iter().map(|s| s.latency).collect();
+        if latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        latencies.sort();
+        let rank = (p / 100.0 * latencies.len() as f64).ceil() as usize;
+        latencies[rank.clamp(1, latencies.len()) - 1]
+    }
+
+    /// Requests per latency range, between the fastest and the slowest one.
+    pub fn histogram(&self) -> Vec<(Duration, Duration, usize)> {
+        let latencies: Vec<_> = self.samples.iter().map(|s| s.latency).collect();
+        let (Some(min), Some(max)) = (latencies.iter().min(), latencies.iter().max()) else {
+            return Vec::new();
+        };
+        let width = (*max - *min) / HISTOGRAM_BUCKETS as u32;
+
+        let mut buckets: Vec<_> = (0..HISTOGRAM_BUCKETS)
+            .map(|i| (*min + width * i as u32, *min + width * (i as u32 + 1), 0))
+            .collect();
+        for latency in &latencies {
+            let i = match width.as_nanos() {
+                0 => 0,
+                w => ((*latency - *min).as_nanos() / w) as usize,
+            };
+            buckets[i.min(HISTOGRAM_BUCKETS - 1)].2 += 1;
+        }
+        buckets
+    }
+
+    pub fn print(&self) {
+        let total = self.samples.len();
+        let errors = self.error_count();
+        println!(
+            "Requests:   {total} in {:.2}s ({:.1} req/s)",
+            self.elapsed.as_secs_f64(),
+            self.throughput()
+        );
+        println!(
+            "Errors:     {errors} ({:.1}%)",
+            errors as f64 * 100.0 / total.max(1) as f64
+        );
+        for (status, count) in self.statuses() {
+            match status {
+                Some(status) => println!("  {status}: {count}"),
+                None => println!("  no response: {count}"),
+            }
+        }
+        if let Some(error) = self.samples.iter().find_map(|s| s.error.as_ref()) {
+            println!("  first error: {error}");
+        }
+
+        println!(
+            "Latency:    p50 {}  p90 {}  p99 {}  max {}",
+            ms(self.percentile(50.0)),
+            ms(self.percentile(90.0)),
+            ms(self.percentile(99.0)),
+            ms(self.percentile(100.0)),
+        );
+
+        let histogram = self.histogram();
+        let highest = histogram.iter().map(|b| b.2).max().unwrap_or(0).max(1);
+        for (from, to, count) in histogram {
+            let bar = "#".repeat(count * HISTOGRAM_WIDTH / highest);
+            println!("  {:>10} - {:>10} | {bar} {count}", ms(from), ms(to));
+        }
+    }
+
+    /// Write one line per request: start and latency in ms, status, error.
+    pub fn write_csv(&self, path: &Path) -> anyhow::Result<()> {
+        let mut writer = csv::Writer::from_path(path)
+            .with_context(|| format!("could not create `{}`", path.display()))?;
+        writer.write_record(["started_ms", "latency_ms", "status", "error"])?;
+        for sample in &self.samples {
+            writer.write_record([
+                format!("{:.3}", sample.started.as_secs_f64() * 1000.0),
+                format!("{:.3}", sample.latency.as_secs_f64() * 1000.0),
+                sample.status.map(|s| s.to_string()).unwrap_or_default(),
+                sample.error.clone().unwrap_or_default(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn ms(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Api, RateLimit, Request};
+    use crate::storage::init_db;
+    use httpmock::prelude::*;
+
+    fn sample(latency_ms: u64, status: Option<u16>) -> Sample {
+        Sample {
+            started: Duration::ZERO,
+            latency: Duration::from_millis(latency_ms),
+            status,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn report_statistics() {
+        let mut samples: Vec<_> = (1..=100).map(|ms| sample(ms, Some(200))).collect();
+        samples[0].status = Some(503);
+        samples[1].status = None;
+        let report = BenchReport {
+            samples,
+            elapsed: Duration::from_secs(2),
+        };
+
+        assert_eq!(report.throughput(), 50.0);
+        assert_eq!(report.error_count(), 2);
+        assert_eq!(report.statuses()[&Some(200)], 98);
+        assert_eq!(report.statuses()[&None], 1);
+        assert_eq!(report.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(report.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(report.percentile(100.0), Duration::from_millis(100));
+
+        let histogram = report.histogram();
+        assert_eq!(histogram.len(), HISTOGRAM_BUCKETS);
+        assert_eq!(histogram.iter().map(|b| b.2).sum::<usize>(), 100);
+        assert_eq!(histogram[0].0, Duration::from_millis(1));
+        assert_eq!(histogram[9].2, 10);
+    }
+
+    #[test]
+    fn bench_sends_the_request_and_writes_samples() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(POST).path("/items").body(r#"{"name":"bob"}"#);
+            then.status(201);
+        });
+
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                ..Default::default()
+            },
+            requests: vec![Request {
+                name: "create".into(),
+                method: "POST".into(),
+                path: "/items".into(),
+                body: Some(r#"{"name":"${name}"}"#.into()),
+                ..Default::default()
+            }],
+            dir: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+        let vars = Vars::from([("name".to_string(), "bob".to_string())]);
+
+        let opts = BenchOptions {
+            requests: Some(20),
+            concurrency: 4,
+            ..Default::default()
+        };
+        let report = run(&conn, &cfg, "create", &vars, &opts).unwrap();
+
+        m.assert_hits(20);
+        assert_eq!(report.samples.len(), 20);
+        assert_eq!(report.statuses()[&Some(201)], 20);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("samples.csv");
+        report.write_csv(&path).unwrap();
+        let csv = std::fs::read_to_string(path).unwrap();
+        assert_eq!(csv.lines().count(), 21);
+        assert!(csv.lines().nth(1).unwrap().contains(",201,"));
+    }
+
+    #[test]
+    fn bench_keeps_to_the_project_rate_limit() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.path("/items");
+            then.status(200);
+        });
+
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                rate_limit: Some(RateLimit::try_from("20/s".to_string()).unwrap()),
+                ..Default::default()
+            },
+            requests: vec![Request {
+                name: "list".into(),
+                method: "GET".into(),
+                path: "/items".into(),
+                ..Default::default()
+            }],
+            dir: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let opts = BenchOptions {
+            requests: Some(5),
+            concurrency: 5,
+            ..Default::default()
+        };
+        let report = run(&conn, &cfg, "list", &Vars::new(), &opts).unwrap();
+
+        m.assert_hits(5);
+        // 50ms apart, even with a worker per request
+        assert!(report.elapsed >= Duration::from_millis(200));
+    }
+}
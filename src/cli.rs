@@ -1,4 +1,7 @@
-use clap::{Parser, Subcommand};
+use crate::config::HumanDuration;
+use crate::output::Output;
+use clap::{Args, Parser, Subcommand};
+use std::time::Duration;
 
 /// Qwest - a CLI-based HTTP client with TOML projects and scripted flows.
 #[derive(Debug, Parser)]
@@ -17,28 +20,10 @@ pub enum Command {
     },
 
     /// Run a request (or scenario) from a project.
-    Run {
-        /// Optional .env file to load variables from.
-        #[arg(long = "env-file")]
-        env_file: Option<String>,
-
-        /// Extra variables, e.g. -e token=1234
-        #[arg(short = 'e', long = "env", value_parser = parse_key_val::<String, String>)]
-        env: Vec<(String, String)>,
-
-        /// Project name (TOML file: ~/.local/share/.qwest/adventures/<project>.toml)
-        project: String,
-
-        /// Route or scenario name
-        ///
-        /// - If matches a request name => single request
-        /// - If matches a scenario name => chained requests
-        route: String,
+    Run(RunArgs),
 
-        /// Optional output format: json, html, raw…
-        #[arg(long, default_value = "json")]
-        format: String,
-    },
+    /// Load test a request of a project.
+    Bench(BenchArgs),
 
     /// Set a variable in the SQLite DB.
     Set {
@@ -60,6 +45,145 @@ pub enum Command {
     Delete {
         project: String,
     },
+
+    /// Manage the cookie jars persisted for a project.
+    Cookies {
+        #[command(subcommand)]
+        action: CookiesAction,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// Optional .env file to load variables from.
+    #[arg(long = "env-file")]
+    pub env_file: Option<String>,
+
+    /// Extra variables, e.g. -e token=1234
+    #[arg(short = 'e', long = "env", value_parser = parse_key_val::<String, String>)]
+    pub env: Vec<(String, String)>,
+
+    /// Project name (TOML file: ~/.local/share/.qwest/adventures/<project>.toml)
+    pub project: String,
+
+    /// Route or scenario name
+    ///
+    /// - If matches a request name => single request
+    /// - If matches a scenario name => chained requests
+    pub route: String,
+
+    /// Optional output format: json, html, raw…
+    #[arg(long, default_value = "json")]
+    pub format: String,
+
+    /// Proxy url (http://, https://, socks5://), overrides the project one
+    #[arg(long)]
+    pub proxy: Option<String>,
+
+    /// CSV or JSON file, the route runs once per row with its columns as variables
+    #[arg(long)]
+    pub data: Option<String>,
+
+    /// How many branches of a parallel group run at once (default: all)
+    #[arg(long)]
+    pub concurrency: Option<usize>,
+
+    /// What to print of each response; with anything but full, only that
+    /// goes to stdout and the progress goes to stderr
+    #[arg(long, value_enum, default_value_t = Output::Full)]
+    pub output: Output,
+
+    /// Also print the requests sent, on stderr
+    #[arg(short, long, conflicts_with = "quiet")]
+    pub verbose: bool,
+
+    /// Print nothing, the exit code tells whether the run passed
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Don't color the output, even in a terminal
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// Print only this part of the JSON responses, as a JSONPath, e.g. '$.data[0].id'
+    #[arg(long, conflicts_with_all = ["output", "query"])]
+    pub select: Option<String>,
+
+    /// Print only this part of the JSON responses, as a JMESPath, e.g. 'data[0].id'
+    #[arg(long, conflicts_with = "output")]
+    pub query: Option<String>,
+
+    /// Write the last response body of the request, or of the scenario's last
+    /// step, to this file once the run is over, e.g. 'out/${id}.pdf'
+    #[arg(long)]
+    pub save: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// Optional .env file to load variables from.
+    #[arg(long = "env-file")]
+    pub env_file: Option<String>,
+
+    /// Extra variables, e.g. -e token=1234
+    #[arg(short = 'e', long = "env", value_parser = parse_key_val::<String, String>)]
+    pub env: Vec<(String, String)>,
+
+    /// Project name
+    pub project: String,
+
+    /// Request name
+    pub route: String,
+
+    /// Requests to send (default 100, unlimited with --duration)
+    #[arg(short = 'n', long)]
+    pub requests: Option<usize>,
+
+    /// Requests in flight at once
+    #[arg(short = 'c', long, default_value_t = 1)]
+    pub concurrency: usize,
+
+    /// Stop after this long, e.g. 30s or 2m
+    #[arg(long, value_parser = parse_duration)]
+    pub duration: Option<Duration>,
+
+    /// Requests per second, across all workers
+    #[arg(long, value_parser = parse_rate)]
+    pub rate: Option<f64>,
+
+    /// Write every request (start, latency, status, error) to this CSV file
+    #[arg(long)]
+    pub csv: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CookiesAction {
+    /// List the persisted cookies of a project.
+    List {
+        project: String,
+    },
+
+    /// Delete the persisted cookies of a project.
+    Clear {
+        project: String,
+
+        /// Only clear the jar used with this env file
+        #[arg(long = "env-file")]
+        env_file: Option<String>,
+    },
+}
+
+/// Parse durations like 500ms, 30s or 2m
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    HumanDuration::try_from(s.to_string()).map(|d| d.0)
+}
+
+/// Parse a positive number of requests per second
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("invalid rate `{s}`, expected a positive number")),
+    }
 }
 
 /// Parse KEY=VALUE pairs
@@ -101,13 +225,14 @@ mod tests {
         .unwrap();
 
         match cli.command {
-            Command::Run {
+            Command::Run(RunArgs {
                 env_file,
                 env,
                 project,
                 route,
                 format,
-            } => {
+                ..
+            }) => {
                 assert_eq!(env_file.as_deref(), Some(".env"));
                 assert_eq!(project, "my_project");
                 assert_eq!(route, "my_route");
@@ -146,5 +271,125 @@ mod tests {
             _ => panic!("expected Set command"),
         }
     }
-}
 
+    #[test]
+    fn parse_run_with_proxy() {
+        let cli = Cli::try_parse_from([
+            "qwest",
+            "run",
+            "my_project",
+            "my_route",
+            "--proxy",
+            "socks5h://localhost:1080",
+        ])
+        .unwrap();
+
+        match cli.command {
+            Command::Run(RunArgs { proxy, .. }) => {
+                assert_eq!(proxy.as_deref(), Some("socks5h://localhost:1080"));
+            }
+            _ => panic!("expected Run command"),
+        }
+    }
+
+    #[test]
+    fn parse_cookies_clear() {
+        let cli = Cli::try_parse_from([
+            "qwest",
+            "cookies",
+            "clear",
+            "proj",
+            "--env-file",
+            "staging.env",
+        ])
+        .unwrap();
+
+        match cli.command {
+            Command::Cookies {
+                action: CookiesAction::Clear { project, env_file },
+            } => {
+                assert_eq!(project, "proj");
+                assert_eq!(env_file.as_deref(), Some("staging.env"));
+            }
+            _ => panic!("expected Cookies Clear command"),
+        }
+    }
+
+    #[test]
+    fn parse_run_with_data() {
+        let cli =
+            Cli::try_parse_from(["qwest", "run", "--data", "users.csv", "my_project", "seed"])
+                .unwrap();
+
+        match cli.command {
+            Command::Run(RunArgs { data, route, .. }) => {
+                assert_eq!(data.as_deref(), Some("users.csv"));
+                assert_eq!(route, "seed");
+            }
+            _ => panic!("expected Run command"),
+        }
+    }
+
+    #[test]
+    fn parse_bench() {
+        let cli = Cli::try_parse_from([
+            "qwest", "bench", "my_project", "list", "-n", "500", "-c", "8", "--duration", "30s",
+            "--rate", "50",
+        ])
+        .unwrap();
+
+        match cli.command {
+            Command::Bench(args) => {
+                assert_eq!(args.requests, Some(500));
+                assert_eq!(args.concurrency, 8);
+                assert_eq!(args.duration, Some(Duration::from_secs(30)));
+                assert_eq!(args.rate, Some(50.0));
+                assert_eq!(args.csv, None);
+            }
+            _ => panic!("expected Bench command"),
+        }
+
+        for rate in ["0", "-5", "NaN", "inf", "fast"] {
+            let parsed = Cli::try_parse_from(["qwest", "bench", "p", "list", "--rate", rate]);
+            assert!(parsed.is_err(), "--rate {rate} should be rejected");
+        }
+    }
+
+    #[test]
+    fn parse_run_output() {
+        let cli = Cli::try_parse_from(["qwest", "run", "p", "r", "--output", "body", "-v"]).unwrap();
+        match cli.command {
+            Command::Run(RunArgs {
+                output,
+                verbose,
+                quiet,
+                ..
+            }) => {
+                assert_eq!(output, Output::Body);
+                assert!(verbose);
+                assert!(!quiet);
+            }
+            _ => panic!("expected Run command"),
+        }
+
+        assert!(Cli::try_parse_from(["qwest", "run", "p", "r", "-v", "-q"]).is_err());
+        assert!(Cli::try_parse_from(["qwest", "run", "p", "r", "--output", "everything"]).is_err());
+    }
+
+    #[test]
+    fn parse_run_selections() {
+        let cli = Cli::try_parse_from(["qwest", "run", "p", "r", "--select", "$.id"]).unwrap();
+        match cli.command {
+            Command::Run(RunArgs { select, query, .. }) => {
+                assert_eq!(select.as_deref(), Some("$.id"));
+                assert_eq!(query, None);
+            }
+            _ => panic!("expected Run command"),
+        }
+
+        let both = ["qwest", "run", "p", "r", "--select", "$.id", "--query", "id"];
+        assert!(Cli::try_parse_from(both).is_err());
+        let with_output = ["qwest", "run", "p", "r", "--query", "id", "--output", "full"];
+        assert!(Cli::try_parse_from(with_output).is_err());
+    }
+}
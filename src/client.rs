@@ -0,0 +1,171 @@
+use crate::config::{Http, HttpVersion, Proxy};
+use crate::cookies::Jar;
+use anyhow::Context;
+use reqwest::blocking::Client;
+use reqwest::redirect::Policy;
+use reqwest::{Certificate, Identity, NoProxy};
+use std::time::Duration;
+
+/// Build the reqwest client for a set of HTTP options, storing cookies in `jar`.
+pub fn build(http: &Http, jar: &Jar) -> anyhow::Result<Client> {
+    let mut builder = Client::builder().cookie_provider(jar.clone());
+
+    if let Some(ms) = http.connect_timeout_ms {
+        builder = builder.connect_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = http.timeout_ms {
+        builder = builder.timeout(Duration::from_millis(ms));
+    }
+
+    builder = match (http.follow_redirects, http.max_redirects) {
+        (Some(false), _) => builder.redirect(Policy::none()),
+        (_, Some(max)) => builder.redirect(Policy::limited(max)),
+        _ => builder,
+    };
+
+    if let Some(accept) = http.accept_invalid_certs {
+        builder = builder.danger_accept_invalid_certs(accept);
+    }
+
+    if let Some(path) = &http.ca_cert {
+        let pem =
+            std::fs::read(path).with_context(|| format!("could not read CA bundle `{path}`"))?;
+        for cert in Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("invalid CA bundle `{path}`"))?
+        {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    match (&http.client_cert, &http.client_key) {
+        (Some(cert), Some(key)) => {
+            let cert_pem = std::fs::read(cert)
+                .with_context(|| format!("could not read client certificate `{cert}`"))?;
+            let key_pem =
+                std::fs::read(key).with_context(|| format!("could not read client key `{key}`"))?;
+            let identity = Identity::from_pkcs8_pem(&cert_pem, &key_pem)
+                .context("invalid client certificate or key")?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => anyhow::bail!("`client_cert` and `client_key` must be set together"),
+    }
+
+    builder = match http.http_version {
+        Some(HttpVersion::Http1) => builder.http1_only(),
+        Some(HttpVersion::Http2) => builder.http2_prior_knowledge(),
+        None => builder,
+    };
+
+    if let Some(proxy) = &http.proxy {
+        builder = builder.proxy(proxy_from(proxy)?);
+    }
+
+    builder.build().context("could not build http client")
+}
+
+fn proxy_from(cfg: &Proxy) -> anyhow::Result<reqwest::Proxy> {
+    let mut proxy = reqwest::Proxy::all(&cfg.url)
+        .with_context(|| format!("invalid proxy url `{}`", cfg.url))?;
+
+    if let Some(username) = &cfg.username {
+        proxy = proxy.basic_auth(username, cfg.password.as_deref().unwrap_or(""));
+    }
+    if !cfg.no_proxy.is_empty() {
+        proxy = proxy.no_proxy(NoProxy::from_string(&cfg.no_proxy.join(",")));
+    }
+
+    Ok(proxy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+
+    #[test]
+    fn redirects_can_be_disabled() {
+        let server = MockServer::start();
+        let old = server.mock(|when, then| {
+            when.method(GET).path("/old");
+            then.status(302).header("Location", "/new");
+        });
+        let new = server.mock(|when, then| {
+            when.method(GET).path("/new");
+            then.status(200);
+        });
+
+        let client = build(
+            &Http {
+                follow_redirects: Some(false),
+                ..Default::default()
+            },
+            &Jar::default(),
+        )
+        .unwrap();
+        let resp = client.get(server.url("/old")).send().unwrap();
+
+        assert_eq!(resp.status(), 302);
+        old.assert();
+        new.assert_hits(0);
+    }
+
+    #[test]
+    fn timeout_aborts_slow_requests() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/slow");
+            then.status(200).delay(Duration::from_millis(500));
+        });
+
+        let client = build(
+            &Http {
+                timeout_ms: Some(50),
+                ..Default::default()
+            },
+            &Jar::default(),
+        )
+        .unwrap();
+
+        let err = client.get(server.url("/slow")).send().unwrap_err();
+        assert!(err.is_timeout());
+    }
+
+    #[test]
+    fn client_cert_requires_key() {
+        let err = build(
+            &Http {
+                client_cert: Some("cert.pem".into()),
+                ..Default::default()
+            },
+            &Jar::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("must be set together"));
+    }
+
+    #[test]
+    fn requests_go_through_proxy() {
+        let proxy = MockServer::start();
+        let m = proxy.mock(|when, then| {
+            when.method(GET).path("/ping");
+            then.status(200).body("pong");
+        });
+
+        let client = build(
+            &Http {
+                proxy: Some(Proxy {
+                    url: proxy.base_url(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            &Jar::default(),
+        )
+        .unwrap();
+        let resp = client.get("http://qwest.invalid/ping").send().unwrap();
+
+        assert_eq!(resp.text().unwrap(), "pong");
+        m.assert();
+    }
+}
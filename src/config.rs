@@ -1,25 +1,69 @@
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct ProjectConfig {
     pub api: Api,
     #[serde(default)]
     pub requests: Vec<Request>,
+
+    /// Directory of the project file, where script modules are looked up.
+    #[serde(skip)]
+    pub dir: Option<PathBuf>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct Api {
     pub name: String,
     pub base_url: String,
 
-    /// Map of scenario name -> ordered list of request names
+    /// Map of scenario name -> ordered list of steps
+    #[serde(default)]
+    pub scenarios: HashMap<String, Vec<Step>>,
+
+    /// Authentication applied to every request (unless overridden).
+    #[serde(default)]
+    pub auth: Option<Auth>,
+
+    /// HTTP client options (timeouts, redirects, TLS).
+    #[serde(default)]
+    pub http: Http,
+
+    /// Keep the cookie jar in the DB between runs (per env file).
+    #[serde(default)]
+    pub persist_cookies: bool,
+
+    /// Script modules and hooks shared by every request.
+    #[serde(default)]
+    pub scripts: ApiScripts,
+
+    /// Retries of every request on transient failures.
+    #[serde(default)]
+    pub retry: Option<Retry>,
+
+    /// Most requests sent to the api, e.g. `"10/s"`, across every branch
+    /// and iteration of a run.
     #[serde(default)]
-    pub scenarios: HashMap<String, Vec<String>>,
+    pub rate_limit: Option<RateLimit>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ApiScripts {
+    /// Rhai files importable by their name, e.g. `scripts/auth.rhai` with
+    /// `import "auth" as auth;`. Paths are relative to the project file,
+    /// next to which any `.rhai` file is importable too.
+    #[serde(default)]
+    pub modules: Vec<String>,
+
+    /// Scripts run around every request: `before` ones ahead of the
+    /// request's own scripts, the others after them.
+    #[serde(default)]
+    pub hooks: Vec<Script>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct Request {
     pub name: String,
     pub method: String,
@@ -36,9 +80,313 @@ pub struct Request {
     /// Scripts attached to this request.
     #[serde(default)]
     pub scripts: Vec<Script>,
+
+    /// Authentication for this request, overrides `api.auth`.
+    #[serde(default)]
+    pub auth: Option<Auth>,
+
+    /// HTTP client options, each field set here overrides `api.http`.
+    #[serde(default)]
+    pub http: Option<Http>,
+
+    /// Requests run before this one, e.g. `needs = ["login"]`, unless
+    /// they are still fresh.
+    #[serde(default)]
+    pub needs: Vec<String>,
+
+    /// Variables set by this request's scripts. When it is needed and they
+    /// are all set already, it doesn't run again.
+    #[serde(default)]
+    pub provides: Vec<String>,
+
+    /// How long what the request provides stays fresh, e.g. `"55m"` for a
+    /// token valid one hour. Unset, it doesn't expire.
+    #[serde(default)]
+    pub fresh_for: Option<HumanDuration>,
+
+    /// Retries on transient failures, each field set here overrides
+    /// `api.retry`.
+    #[serde(default)]
+    pub retry: Option<Retry>,
+
+    /// Variables set from the JSON response, by JSONPath, e.g.
+    /// `extract = { user_id = "$.id" }`. They are saved in the database.
+    #[serde(default)]
+    pub extract: HashMap<String, String>,
+
+    /// File the response body is written to, with placeholders, e.g.
+    /// `"out/${name}.pdf"`. Scripts get its path as `response_file`.
+    #[serde(default)]
+    pub save_to: Option<String>,
+}
+
+/// Options of the HTTP client, unset fields keep reqwest defaults.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct Http {
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    /// Whole request, until the response body is read
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// `false` returns 3xx responses as-is
+    #[serde(default)]
+    pub follow_redirects: Option<bool>,
+    #[serde(default)]
+    pub max_redirects: Option<usize>,
+    #[serde(default)]
+    pub accept_invalid_certs: Option<bool>,
+    /// PEM file with extra root certificates
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    /// PEM certificate and its PKCS#8 key for mTLS
+    #[serde(default)]
+    pub client_cert: Option<String>,
+    #[serde(default)]
+    pub client_key: Option<String>,
+    #[serde(default)]
+    pub http_version: Option<HttpVersion>,
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+}
+
+impl Http {
+    /// `self` with every field set in `other` overridden.
+    pub fn merged(&self, other: &Http) -> Http {
+        Http {
+            connect_timeout_ms: other.connect_timeout_ms.or(self.connect_timeout_ms),
+            timeout_ms: other.timeout_ms.or(self.timeout_ms),
+            follow_redirects: other.follow_redirects.or(self.follow_redirects),
+            max_redirects: other.max_redirects.or(self.max_redirects),
+            accept_invalid_certs: other.accept_invalid_certs.or(self.accept_invalid_certs),
+            ca_cert: other.ca_cert.clone().or_else(|| self.ca_cert.clone()),
+            client_cert: other.client_cert.clone().or_else(|| self.client_cert.clone()),
+            client_key: other.client_key.clone().or_else(|| self.client_key.clone()),
+            http_version: other.http_version.or(self.http_version),
+            proxy: other.proxy.clone().or_else(|| self.proxy.clone()),
+        }
+    }
+}
+
+/// `retry = { attempts = 3, backoff = "exponential", base_ms = 200 }`,
+/// unset fields take the defaults below.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct Retry {
+    /// Attempts in all, the first one included (default 3)
+    #[serde(default)]
+    pub attempts: Option<u32>,
+    /// Growth of the wait between attempts (default exponential)
+    #[serde(default)]
+    pub backoff: Option<Backoff>,
+    /// Wait before the first retry (default 200)
+    #[serde(default)]
+    pub base_ms: Option<u64>,
+    /// Statuses retried (default 429, 502, 503 and 504)
+    #[serde(default)]
+    pub on_status: Option<Vec<u16>>,
+    /// Retry when no response was received (default true)
+    #[serde(default)]
+    pub on_network_error: Option<bool>,
+}
+
+impl Retry {
+    /// `self` with every field set in `other` overridden.
+    pub fn merged(&self, other: &Retry) -> Retry {
+        Retry {
+            attempts: other.attempts.or(self.attempts),
+            backoff: other.backoff.or(self.backoff),
+            base_ms: other.base_ms.or(self.base_ms),
+            on_status: other.on_status.clone().or_else(|| self.on_status.clone()),
+            on_network_error: other.on_network_error.or(self.on_network_error),
+        }
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts.unwrap_or(3).max(1)
+    }
+
+    pub fn retries_status(&self, status: u16) -> bool {
+        match &self.on_status {
+            Some(statuses) => statuses.contains(&status),
+            None => matches!(status, 429 | 502 | 503 | 504),
+        }
+    }
+
+    pub fn retries_network_errors(&self) -> bool {
+        self.on_network_error.unwrap_or(true)
+    }
+
+    /// Wait after the failed attempt number `attempt`, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = Duration::from_millis(self.base_ms.unwrap_or(200));
+        match self.backoff.unwrap_or_default() {
+            Backoff::Fixed => base,
+            Backoff::Linear => base.saturating_mul(attempt),
+            Backoff::Exponential => {
+                base.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backoff {
+    Fixed,
+    Linear,
+    #[default]
+    Exponential,
+}
+
+/// Proxy used for every scheme: `http://`, `https://`, `socks5://` or
+/// `socks5h://` (DNS resolved by the proxy).
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct Proxy {
+    pub url: String,
+    /// Hosts reached directly, e.g. `["localhost", ".internal.example.com"]`
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpVersion {
+    Http1,
+    Http2,
+}
+
+/// Authentication scheme, e.g. `[api.auth.bearer]` or
+/// `auth = { basic = { username = "me", password = "${pwd}" } }`.
+///
+/// Every field may contain placeholders.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Auth {
+    Basic {
+        username: String,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    Bearer {
+        token: String,
+    },
+    ApiKey {
+        /// Header or query parameter name
+        name: String,
+        value: String,
+        #[serde(default)]
+        location: ApiKeyLocation,
+    },
+    /// Challenge-based: the request is sent once, then replayed with the
+    /// answer to the server's `WWW-Authenticate` header.
+    Digest {
+        username: String,
+        password: String,
+    },
+    /// Token fetched from `token_url`, cached in the DB until it expires,
+    /// then sent as a bearer token.
+    #[serde(rename = "oauth2")]
+    OAuth2(OAuth2),
+    /// AWS Signature Version 4, computed over the final request.
+    #[serde(rename = "aws_sigv4")]
+    AwsSigV4(AwsSigV4),
+    /// Generic HMAC signature over a configurable string to sign.
+    Hmac(Hmac),
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub struct OAuth2 {
+    pub token_url: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub grant_type: GrantType,
+    /// Initial refresh token, required by the `refresh_token` grant.
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub struct AwsSigV4 {
+    pub access_key: String,
+    pub secret_key: String,
+    #[serde(default)]
+    pub session_token: Option<String>,
+    pub region: String,
+    /// e.g. `execute-api`, `s3`
+    pub service: String,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Hmac {
+    pub secret: String,
+    #[serde(default)]
+    pub algorithm: HmacAlgorithm,
+    /// Header receiving the signature
+    #[serde(default = "default_signature_header")]
+    pub header: String,
+    /// Prepended to the signature, e.g. `"HMAC "`
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub encoding: SignatureEncoding,
+    /// Template of the signed string, accepts `{method}`, `{path}`, `{query}`,
+    /// `{host}`, `{body}`, `{body_sha256}`, `{timestamp}` and `{header:<name>}`.
+    #[serde(default = "default_string_to_sign")]
+    pub string_to_sign: String,
+    /// Header receiving the unix timestamp used as `{timestamp}`
+    #[serde(default)]
+    pub timestamp_header: Option<String>,
+}
+
+fn default_signature_header() -> String {
+    "X-Signature".into()
+}
+
+fn default_string_to_sign() -> String {
+    "{method}\n{path}\n{body_sha256}".into()
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HmacAlgorithm {
+    Sha1,
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GrantType {
+    #[default]
+    ClientCredentials,
+    RefreshToken,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyLocation {
+    #[default]
+    Header,
+    Query,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct Script {
     /// true => run before request
     /// false => run after request
@@ -50,6 +398,261 @@ pub struct Script {
     /// Optional description shown before execution
     #[serde(default)]
     pub description: Option<String>,
+
+    /// Which returned variables are saved in the DB, the others only
+    /// live for the current run.
+    #[serde(default)]
+    pub persist: Persist,
+}
+
+/// `persist = true`, `persist = false` or `persist = ["token"]`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Persist {
+    All(bool),
+    Only(Vec<String>),
+}
+
+impl Default for Persist {
+    fn default() -> Self {
+        Persist::All(true)
+    }
+}
+
+impl Persist {
+    pub fn includes(&self, label: &str) -> bool {
+        match self {
+            Persist::All(all) => *all,
+            Persist::Only(labels) => labels.iter().any(|l| l == label),
+        }
+    }
+}
+
+/// Step of a scenario: a request name, a table to run it conditionally,
+/// or a group of branches run concurrently:
+///
+/// ```toml
+/// [api.scenarios]
+/// signup = [
+///   "lookup",
+///   { request = "create_user", if = "response.status == 404" },
+///   { request = "profile", skip_unless = "user_id", on_failure = "goto:lookup" },
+///   { parallel = ["avatar", ["settings", "notifications"]] },
+/// ]
+/// ```
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Step {
+    Request(String),
+    Parallel(ParallelGroup),
+    Table(StepTable),
+}
+
+impl Step {
+    /// The request step as a table, `None` for a parallel group.
+    pub fn table(&self) -> Option<StepTable> {
+        match self {
+            Step::Request(request) => Some(StepTable {
+                request: request.clone(),
+                ..Default::default()
+            }),
+            Step::Table(table) => Some(table.clone()),
+            Step::Parallel(_) => None,
+        }
+    }
+
+    /// Name `goto:` jumps to.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Step::Request(request) => Some(request),
+            Step::Table(table) => Some(table.name()),
+            Step::Parallel(group) => group.name.as_deref(),
+        }
+    }
+}
+
+impl From<&str> for Step {
+    fn from(request: &str) -> Self {
+        Step::Request(request.into())
+    }
+}
+
+/// Branches run concurrently, each one a step or a list of steps.
+/// Variables set by several branches keep the value of the last one.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ParallelGroup {
+    pub parallel: Vec<Branch>,
+
+    /// Name `goto:` jumps to
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Branch {
+    Steps(Vec<Step>),
+    Step(Box<Step>),
+}
+
+impl Branch {
+    pub fn steps(&self) -> &[Step] {
+        match self {
+            Branch::Steps(steps) => steps,
+            Branch::Step(step) => std::slice::from_ref(step.as_ref()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct StepTable {
+    /// Name of the request to run
+    pub request: String,
+
+    /// Name `goto:` jumps to, defaults to the request name
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Rhai expression, the step is skipped unless it is true. The
+    /// variables and the previous `response` are in scope.
+    #[serde(default, rename = "if")]
+    pub condition: Option<String>,
+
+    /// Variable the step needs, skipped while it is unset or empty.
+    #[serde(default)]
+    pub skip_unless: Option<String>,
+
+    /// Rhai expression checked after each attempt, the request is sent
+    /// again until it is true. `response` is the one just received.
+    #[serde(default)]
+    pub until: Option<String>,
+
+    /// Wait between two attempts of `until` (default 1s)
+    #[serde(default)]
+    pub interval: Option<HumanDuration>,
+
+    /// Attempts of `until` before the step fails (default 10)
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+
+    /// Rhai expression giving a list (or a variable holding a JSON
+    /// array), the request runs once per item.
+    #[serde(default)]
+    pub for_each: Option<String>,
+
+    /// Variable holding the current item of `for_each` (default `item`)
+    #[serde(default, rename = "as")]
+    pub item: Option<String>,
+
+    /// What to do when the request fails or one of its checks does.
+    /// Unset, errors stop the scenario and failed checks don't.
+    #[serde(default)]
+    pub on_failure: Option<OnFailure>,
+
+    /// Wait before sending the request, before each item of `for_each`
+    #[serde(default)]
+    pub delay_ms: Option<u64>,
+}
+
+impl StepTable {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.request)
+    }
+
+    pub fn item_var(&self) -> &str {
+        self.item.as_deref().unwrap_or("item")
+    }
+}
+
+/// `"10/s"`, `"100/m"` or `"5/10s"`: `requests` at most per `period`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    /// Time between two requests to stay under the limit.
+    pub fn interval(&self) -> Duration {
+        self.period / self.requests
+    }
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid rate limit `{value}`, expected e.g. 10/s or 100/m");
+        let (requests, period) = value.split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let period = period.trim();
+        // "10/s" is "10/1s"
+        let period = match period.starts_with(|c: char| c.is_ascii_digit()) {
+            true => HumanDuration::try_from(period.to_string()),
+            false => HumanDuration::try_from(format!("1{period}")),
+        }
+        .map_err(|_| invalid())?;
+        if requests == 0 || period.0.is_zero() {
+            return Err(invalid());
+        }
+        Ok(RateLimit {
+            requests,
+            period: period.0,
+        })
+    }
+}
+
+/// Duration written `"500ms"`, `"2s"`, `"1m"` or `"1h"`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct HumanDuration(pub Duration);
+
+impl TryFrom<String> for HumanDuration {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let split = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (amount, unit) = value.split_at(split);
+        let invalid = || format!("invalid duration `{value}`");
+        let amount: u64 = amount.parse().map_err(|_| invalid())?;
+        let duration = match unit.trim() {
+            "ms" => Duration::from_millis(amount),
+            "s" => Duration::from_secs(amount),
+            "m" => Duration::from_secs(amount.checked_mul(60).ok_or_else(invalid)?),
+            "h" => Duration::from_secs(amount.checked_mul(3600).ok_or_else(invalid)?),
+            _ => return Err(format!("invalid duration `{value}`, expected e.g. 500ms, 2s, 1m or 1h")),
+        };
+        Ok(HumanDuration(duration))
+    }
+}
+
+/// `on_failure = "continue" | "abort" | "goto:<step>"`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub enum OnFailure {
+    Continue,
+    Abort,
+    Goto(String),
+}
+
+impl TryFrom<String> for OnFailure {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "continue" => Ok(OnFailure::Continue),
+            "abort" => Ok(OnFailure::Abort),
+            _ => match value.strip_prefix("goto:") {
+                Some(step) if !step.is_empty() => Ok(OnFailure::Goto(step.into())),
+                _ => Err(format!(
+                    "invalid on_failure `{value}`, expected continue, abort or goto:<step>"
+                )),
+            },
+        }
+    }
 }
 
 // ---------- helper used from main.rs ----------
@@ -66,7 +669,6 @@ pub fn project_toml_path(project: &str) -> anyhow::Result<PathBuf> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use toml;
 
     #[test]
     fn parse_minimal_project_config() {
@@ -93,7 +695,10 @@ mod tests {
 
         assert_eq!(cfg.api.name, "test");
         assert_eq!(cfg.api.base_url, "https://example.com");
-        assert_eq!(cfg.api.scenarios["first"], vec!["docs", "login"]);
+        assert_eq!(
+            cfg.api.scenarios["first"],
+            vec![Step::from("docs"), Step::from("login")]
+        );
         assert_eq!(cfg.requests.len(), 2);
 
         let docs = &cfg.requests[0];
@@ -125,8 +730,429 @@ mod tests {
         assert_eq!(cfg.requests.len(), 1);
         let req = &cfg.requests[0];
         assert_eq!(req.scripts.len(), 1);
-        assert_eq!(req.scripts[0].before, false);
+        assert!(!req.scripts[0].before);
         assert_eq!(req.scripts[0].description.as_deref(), Some("Store job id"));
     }
-}
 
+    #[test]
+    fn parse_api_and_request_auth() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+
+            [api.auth.bearer]
+            token = "${token}"
+
+            [[requests]]
+            name = "admin"
+            method = "GET"
+            path = "/admin"
+            auth = { api_key = { name = "key", value = "${key}", location = "query" } }
+
+            [[requests]]
+            name = "legacy"
+            method = "GET"
+            path = "/legacy"
+
+              [requests.auth.digest]
+              username = "me"
+              password = "secret"
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+
+        assert_eq!(
+            cfg.api.auth,
+            Some(Auth::Bearer {
+                token: "${token}".into()
+            })
+        );
+        assert_eq!(
+            cfg.requests[0].auth,
+            Some(Auth::ApiKey {
+                name: "key".into(),
+                value: "${key}".into(),
+                location: ApiKeyLocation::Query,
+            })
+        );
+        assert!(matches!(cfg.requests[1].auth, Some(Auth::Digest { .. })));
+    }
+
+    #[test]
+    fn parse_oauth2_auth() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+
+            [api.auth.oauth2]
+            token_url = "https://auth.example.com/token"
+            client_id = "qwest"
+            client_secret = "${client_secret}"
+            scopes = ["read", "write"]
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+
+        let Some(Auth::OAuth2(oauth)) = cfg.api.auth else {
+            panic!("expected oauth2 auth");
+        };
+        assert_eq!(oauth.token_url, "https://auth.example.com/token");
+        assert_eq!(oauth.client_secret.as_deref(), Some("${client_secret}"));
+        assert_eq!(oauth.scopes, vec!["read", "write"]);
+        assert_eq!(oauth.grant_type, GrantType::ClientCredentials);
+    }
+
+    #[test]
+    fn parse_signing_auth() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+
+            [[requests]]
+            name = "aws"
+            method = "GET"
+            path = "/items"
+
+              [requests.auth.aws_sigv4]
+              access_key = "${aws_access_key}"
+              secret_key = "${aws_secret_key}"
+              region = "eu-west-1"
+              service = "execute-api"
+
+            [[requests]]
+            name = "internal"
+            method = "POST"
+            path = "/orders"
+
+              [requests.auth.hmac]
+              secret = "${hmac_secret}"
+              algorithm = "sha512"
+              encoding = "base64"
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+
+        let Some(Auth::AwsSigV4(aws)) = &cfg.requests[0].auth else {
+            panic!("expected aws_sigv4 auth");
+        };
+        assert_eq!(aws.region, "eu-west-1");
+        assert!(aws.session_token.is_none());
+
+        let Some(Auth::Hmac(hmac)) = &cfg.requests[1].auth else {
+            panic!("expected hmac auth");
+        };
+        assert_eq!(hmac.algorithm, HmacAlgorithm::Sha512);
+        assert_eq!(hmac.encoding, SignatureEncoding::Base64);
+        assert_eq!(hmac.header, "X-Signature");
+        assert_eq!(hmac.string_to_sign, "{method}\n{path}\n{body_sha256}");
+    }
+
+    #[test]
+    fn request_http_options_override_api_ones() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://staging.example.com"
+
+            [api.http]
+            timeout_ms = 5000
+            accept_invalid_certs = true
+
+            [[requests]]
+            name = "redirect"
+            method = "GET"
+            path = "/old"
+            http = { follow_redirects = false, timeout_ms = 1000 }
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+        let merged = cfg
+            .api
+            .http
+            .merged(cfg.requests[0].http.as_ref().unwrap());
+
+        assert_eq!(merged.timeout_ms, Some(1000));
+        assert_eq!(merged.follow_redirects, Some(false));
+        assert_eq!(merged.accept_invalid_certs, Some(true));
+        assert_eq!(merged.http_version, None);
+    }
+
+    #[test]
+    fn parse_proxy() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+
+            [api.http.proxy]
+            url = "socks5h://localhost:1080"
+            no_proxy = ["localhost", ".internal"]
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+        let proxy = cfg.api.http.proxy.unwrap();
+
+        assert_eq!(proxy.url, "socks5h://localhost:1080");
+        assert_eq!(proxy.no_proxy, vec!["localhost", ".internal"]);
+        assert!(proxy.username.is_none());
+    }
+
+    #[test]
+    fn parse_script_persist() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+
+            [[requests]]
+            name = "job"
+            method = "POST"
+            path = "/jobs"
+
+              [[requests.scripts]]
+              before = false
+              script = "return #{ job_id: 1 };"
+              persist = false
+
+              [[requests.scripts]]
+              before = false
+              script = "return #{ token: 1, tmp: 2 };"
+              persist = ["token"]
+
+              [[requests.scripts]]
+              before = false
+              script = "return #{ other: 1 };"
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+        let scripts = &cfg.requests[0].scripts;
+
+        assert_eq!(scripts[0].persist, Persist::All(false));
+        assert!(!scripts[0].persist.includes("job_id"));
+        assert!(scripts[1].persist.includes("token"));
+        assert!(!scripts[1].persist.includes("tmp"));
+        assert!(scripts[2].persist.includes("other"));
+    }
+
+    #[test]
+    fn parse_api_scripts() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+
+            [api.scripts]
+            modules = ["scripts/auth.rhai"]
+
+              [[api.scripts.hooks]]
+              before = true
+              script = "import \"auth\" as auth; auth::refresh();"
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+
+        assert_eq!(cfg.api.scripts.modules, vec!["scripts/auth.rhai"]);
+        assert_eq!(cfg.api.scripts.hooks.len(), 1);
+        assert!(cfg.api.scripts.hooks[0].before);
+    }
+
+    #[test]
+    fn parse_conditional_steps() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+
+            [api.scenarios]
+            signup = [
+              "lookup",
+              { request = "create_user", if = "response.status == 404", on_failure = "abort" },
+              { request = "profile", name = "me", skip_unless = "user_id", on_failure = "goto:lookup" },
+            ]
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+        let steps: Vec<StepTable> = cfg.api.scenarios["signup"]
+            .iter()
+            .filter_map(Step::table)
+            .collect();
+
+        assert_eq!(steps[0].name(), "lookup");
+        assert_eq!(steps[0].on_failure, None);
+        assert_eq!(steps[1].condition.as_deref(), Some("response.status == 404"));
+        assert_eq!(steps[1].on_failure, Some(OnFailure::Abort));
+        assert_eq!(steps[2].name(), "me");
+        assert_eq!(steps[2].skip_unless.as_deref(), Some("user_id"));
+        assert_eq!(steps[2].on_failure, Some(OnFailure::Goto("lookup".into())));
+
+        let invalid = r#"
+            [api]
+            name = "test"
+            base_url = ""
+            scenarios = { s = [{ request = "a", on_failure = "retry" }] }
+        "#;
+        assert!(toml::from_str::<ProjectConfig>(invalid).is_err());
+    }
+
+    #[test]
+    fn parse_loop_steps() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+
+            [api.scenarios]
+            job = [
+              "create_job",
+              { request = "job_status", until = "response.json().status == \"done\"", interval = "500ms", max_attempts = 20 },
+              { request = "get_user", for_each = "user_ids", as = "user_id" },
+            ]
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+        let steps: Vec<StepTable> = cfg.api.scenarios["job"]
+            .iter()
+            .filter_map(Step::table)
+            .collect();
+
+        assert_eq!(
+            steps[1].interval,
+            Some(HumanDuration(Duration::from_millis(500)))
+        );
+        assert_eq!(steps[1].max_attempts, Some(20));
+        assert_eq!(steps[2].for_each.as_deref(), Some("user_ids"));
+        assert_eq!(steps[2].item_var(), "user_id");
+        assert_eq!(steps[0].item_var(), "item");
+
+        assert_eq!(
+            HumanDuration::try_from("1m".to_string()),
+            Ok(HumanDuration(Duration::from_secs(60)))
+        );
+        assert!(HumanDuration::try_from("2 weeks".to_string()).is_err());
+        assert_eq!(
+            HumanDuration::try_from(format!("{}h", u64::MAX / 60)),
+            Err(format!("invalid duration `{}h`", u64::MAX / 60))
+        );
+    }
+
+    #[test]
+    fn parse_parallel_groups() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+
+            [api.scenarios]
+            warmup = [
+              "login",
+              { parallel = ["a", ["b", { request = "c", if = "true" }]], name = "fan_out" },
+            ]
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+        let steps = &cfg.api.scenarios["warmup"];
+
+        let Step::Parallel(group) = &steps[1] else {
+            panic!("expected a parallel group, got {:?}", steps[1]);
+        };
+        assert_eq!(steps[1].name(), Some("fan_out"));
+        assert_eq!(group.parallel[0].steps(), &[Step::from("a")]);
+        assert_eq!(group.parallel[1].steps().len(), 2);
+        assert_eq!(group.parallel[1].steps()[1].name(), Some("c"));
+    }
+
+    #[test]
+    fn parse_request_needs() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+
+            [[requests]]
+            name = "login"
+            method = "POST"
+            path = "/login"
+            provides = ["token"]
+            fresh_for = "1h"
+
+            [[requests]]
+            name = "user"
+            method = "GET"
+            path = "/user"
+            needs = ["login"]
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+        assert_eq!(cfg.requests[0].provides, vec!["token"]);
+        assert_eq!(
+            cfg.requests[0].fresh_for,
+            Some(HumanDuration(Duration::from_secs(3600)))
+        );
+        assert!(cfg.requests[0].needs.is_empty());
+        assert_eq!(cfg.requests[1].needs, vec!["login"]);
+    }
+
+    #[test]
+    fn parse_retry() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+            retry = { attempts = 4, base_ms = 100, on_status = [503] }
+
+            [[requests]]
+            name = "flaky"
+            method = "GET"
+            path = "/flaky"
+            retry = { backoff = "linear", on_network_error = false }
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+        let api = cfg.api.retry.unwrap();
+        let retry = api.merged(cfg.requests[0].retry.as_ref().unwrap());
+
+        assert_eq!(retry.attempts(), 4);
+        assert!(retry.retries_status(503));
+        assert!(!retry.retries_status(502));
+        assert!(!retry.retries_network_errors());
+        assert_eq!(retry.delay(3), Duration::from_millis(300));
+        assert_eq!(api.delay(1), Duration::from_millis(100));
+        assert_eq!(api.delay(3), Duration::from_millis(400));
+
+        let default = Retry::default();
+        assert_eq!(default.attempts(), 3);
+        assert!(default.retries_status(429));
+        assert!(default.retries_network_errors());
+    }
+
+    #[test]
+    fn parse_rate_limit_and_delays() {
+        let toml = r#"
+            [api]
+            name = "test"
+            base_url = "https://example.com"
+            rate_limit = "10/s"
+
+            [api.scenarios]
+            slow = ["a", { request = "b", delay_ms = 250 }]
+        "#;
+
+        let cfg: ProjectConfig = toml::from_str(toml).unwrap();
+        let limit = cfg.api.rate_limit.unwrap();
+        assert_eq!(limit.interval(), Duration::from_millis(100));
+        let steps: Vec<StepTable> = cfg.api.scenarios["slow"]
+            .iter()
+            .filter_map(Step::table)
+            .collect();
+        assert_eq!(steps[1].delay_ms, Some(250));
+
+        let parse = |s: &str| RateLimit::try_from(s.to_string());
+        assert_eq!(parse("120/m").unwrap().interval(), Duration::from_millis(500));
+        assert_eq!(parse("5/10s").unwrap().interval(), Duration::from_secs(2));
+        assert!(parse("0/s").is_err());
+        assert!(parse("10 per second").is_err());
+    }
+}
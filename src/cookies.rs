@@ -0,0 +1,104 @@
+use crate::storage;
+use anyhow::Context;
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use rusqlite::Connection;
+use std::sync::Arc;
+
+/// Cookie store shared by every client of a run.
+pub type Jar = Arc<CookieStoreMutex>;
+
+/// Cookie listed by `qwest cookies list`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredCookie {
+    pub environment: Option<String>,
+    pub name: String,
+    pub value: String,
+    pub domain: String,
+    pub path: String,
+}
+
+/// Load the persisted jar of a project and environment, empty if none.
+pub fn load(conn: &Connection, project: &str, environment: Option<&str>) -> anyhow::Result<Jar> {
+    let store = match storage::load_cookie_jar(conn, project, environment)? {
+        Some(json) => parse(&json)?,
+        None => CookieStore::default(),
+    };
+    Ok(Arc::new(CookieStoreMutex::new(store)))
+}
+
+/// Persist the jar, session cookies included since session-based APIs
+/// rarely set an expiry.
+pub fn save(
+    conn: &Connection,
+    project: &str,
+    environment: Option<&str>,
+    jar: &Jar,
+) -> anyhow::Result<()> {
+    let store = jar
+        .lock()
+        .map_err(|_| anyhow::anyhow!("cookie jar lock poisoned"))?;
+    let mut json = Vec::new();
+    cookie_store::serde::json::save_incl_expired_and_nonpersistent(&store, &mut json)
+        .map_err(|e| anyhow::anyhow!("could not serialize cookies: {e}"))?;
+    storage::save_cookie_jar(conn, project, environment, &String::from_utf8(json)?)
+        .context("failed to persist cookies")?;
+    Ok(())
+}
+
+/// Unexpired cookies of every environment of a project.
+pub fn list(conn: &Connection, project: &str) -> anyhow::Result<Vec<StoredCookie>> {
+    let mut cookies = Vec::new();
+    for (environment, json) in storage::load_cookie_jars(conn, project)? {
+        let store = parse(&json)?;
+        for cookie in store.iter_unexpired() {
+            cookies.push(StoredCookie {
+                environment: environment.clone(),
+                name: cookie.name().to_string(),
+                value: cookie.value().to_string(),
+                domain: String::from(&cookie.domain),
+                path: String::from(&cookie.path),
+            });
+        }
+    }
+    Ok(cookies)
+}
+
+fn parse(json: &str) -> anyhow::Result<CookieStore> {
+    cookie_store::serde::json::load(json.as_bytes())
+        .map_err(|e| anyhow::anyhow!("invalid persisted cookies: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::init_db;
+
+    #[test]
+    fn jar_roundtrips_through_db() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let jar = load(&conn, "p", Some("staging.env")).unwrap();
+        let url = "https://example.com/login".parse().unwrap();
+        jar.lock()
+            .unwrap()
+            .parse("session=abc; Path=/", &url)
+            .unwrap();
+        save(&conn, "p", Some("staging.env"), &jar).unwrap();
+
+        let cookies = list(&conn, "p").unwrap();
+        assert_eq!(cookies.len(), 1);
+        assert_eq!(cookies[0].environment.as_deref(), Some("staging.env"));
+        assert_eq!(cookies[0].name, "session");
+        assert_eq!(cookies[0].value, "abc");
+        assert_eq!(cookies[0].domain, "example.com");
+
+        let reloaded = load(&conn, "p", Some("staging.env")).unwrap();
+        assert!(reloaded
+            .lock()
+            .unwrap()
+            .contains("example.com", "/", "session"));
+        let other = load(&conn, "p", None).unwrap();
+        assert_eq!(other.lock().unwrap().iter_any().count(), 0);
+    }
+}
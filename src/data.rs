@@ -0,0 +1,79 @@
+use crate::extract::to_var;
+use crate::templating::Vars;
+use anyhow::Context;
+use std::path::Path;
+
+/// Rows of a `--data` file, one set of variables per row.
+///
+/// `.json` files hold an array of objects, anything else is read as CSV
+/// with a header line naming the variables.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Vars>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read data file `{}`", path.display()))?;
+
+    let rows = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => parse_json(&content),
+        _ => parse_csv(&content),
+    };
+    rows.with_context(|| format!("invalid data file `{}`", path.display()))
+}
+
+fn parse_csv(content: &str) -> anyhow::Result<Vec<Vars>> {
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let headers = reader.headers()?.clone();
+
+    reader
+        .records()
+        .map(|record| {
+            let record = record?;
+            Ok(headers
+                .iter()
+                .zip(record.iter())
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect())
+        })
+        .collect()
+}
+
+/// Strings are taken as is, other values as JSON.
+fn parse_json(content: &str) -> anyhow::Result<Vec<Vars>> {
+    let rows: Vec<serde_json::Map<String, serde_json::Value>> =
+        serde_json::from_str(content).context("expected an array of objects")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|(k, v)| (k, to_var(v)))
+                .collect()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_csv_and_json_rows() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let csv = dir.path().join("users.csv");
+        std::fs::write(&csv, "email,role\na@x.io,admin\n\"b,c@x.io\",user\n").unwrap();
+        let rows = load(&csv).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["email"], "a@x.io");
+        assert_eq!(rows[1]["email"], "b,c@x.io");
+        assert_eq!(rows[1]["role"], "user");
+
+        let json = dir.path().join("users.json");
+        std::fs::write(&json, r#"[{"email":"a@x.io","age":31,"tags":["x"]}]"#).unwrap();
+        let rows = load(&json).unwrap();
+        assert_eq!(rows[0]["email"], "a@x.io");
+        assert_eq!(rows[0]["age"], "31");
+        assert_eq!(rows[0]["tags"], r#"["x"]"#);
+
+        std::fs::write(&json, r#"{"email":"a@x.io"}"#).unwrap();
+        assert!(load(&json).is_err());
+    }
+}
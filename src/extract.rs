@@ -0,0 +1,162 @@
+use crate::scripting::AssertionFailure;
+use crate::storage::VariableStore;
+use crate::templating::Vars;
+use anyhow::Context;
+use serde_json::Value;
+use serde_json_path::JsonPath;
+use std::collections::HashMap;
+
+/// Part of a JSON response picked by `--select` or `--query`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selector {
+    /// JSONPath, e.g. `$.data[0].id`
+    JsonPath(String),
+    /// JMESPath, e.g. `data[0].id`
+    JmesPath(String),
+}
+
+impl Selector {
+    /// The selected value, `None` when nothing matched.
+    pub fn select(&self, body: &str) -> anyhow::Result<Option<Value>> {
+        let json: Value = serde_json::from_str(body).context("the response is not JSON")?;
+        match self {
+            Selector::JsonPath(path) => json_path(&json, path),
+            Selector::JmesPath(expr) => {
+                let found = jmespath::compile(expr)
+                    .with_context(|| format!("invalid JMESPath `{expr}`"))?
+                    .search(&json)?;
+                match serde_json::to_value(&*found)? {
+                    Value::Null => Ok(None),
+                    value => Ok(Some(value)),
+                }
+            }
+        }
+    }
+}
+
+/// Value matched by a JSONPath: the only node, or an array of all of them.
+pub fn json_path(json: &Value, path: &str) -> anyhow::Result<Option<Value>> {
+    let path = JsonPath::parse(path).with_context(|| format!("invalid JSONPath `{path}`"))?;
+    let mut nodes = path.query(json).all();
+    Ok(match nodes.len() {
+        0 => None,
+        1 => nodes.pop().cloned(),
+        _ => Some(Value::Array(nodes.into_iter().cloned().collect())),
+    })
+}
+
+/// Set the variables of an `extract` table from a response body, and save
+/// them for `project`. What can't be extracted is reported as a failure of
+/// the request.
+pub fn extract(
+    store: &dyn VariableStore,
+    project: &str,
+    extract: &HashMap<String, String>,
+    body: &str,
+    vars: &mut Vars,
+) -> anyhow::Result<Vec<AssertionFailure>> {
+    let failure = |label: &str, message: String| AssertionFailure {
+        script: format!("extract `{label}`"),
+        message,
+    };
+    if extract.is_empty() {
+        return Ok(Vec::new());
+    }
+    let json: Value = match serde_json::from_str(body) {
+        Ok(json) => json,
+        Err(_) => {
+            return Ok(extract
+                .keys()
+                .map(|label| failure(label, "the response is not JSON".into()))
+                .collect());
+        }
+    };
+
+    let mut failures = Vec::new();
+    for (label, path) in extract {
+        match json_path(&json, path) {
+            Ok(Some(value)) => {
+                let value = to_var(value);
+                store
+                    .set_variable(label, &value, Some(project))
+                    .with_context(|| format!("failed to persist extracted variable `{label}`"))?;
+                vars.insert(label.clone(), value);
+            }
+            Ok(None) => failures.push(failure(label, format!("`{path}` matched nothing"))),
+            Err(e) => failures.push(failure(label, format!("{e:#}"))),
+        }
+    }
+    Ok(failures)
+}
+
+/// Strings are taken as is, other values as JSON.
+pub fn to_var(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{init_db, load_variables, variable_updated_at};
+    use rusqlite::Connection;
+
+    const BODY: &str = r#"{"data": [{"id": 7, "name": "bob"}, {"id": 8, "name": "eve"}]}"#;
+
+    #[test]
+    fn selectors_pick_parts_of_the_body() {
+        let select = |s: Selector| s.select(BODY).unwrap();
+
+        assert_eq!(
+            select(Selector::JsonPath("$.data[0].id".into())),
+            Some(7.into())
+        );
+        assert_eq!(
+            select(Selector::JsonPath("$.data[*].name".into())),
+            Some(serde_json::json!(["bob", "eve"]))
+        );
+        assert_eq!(select(Selector::JsonPath("$.missing".into())), None);
+        assert_eq!(
+            select(Selector::JmesPath("data[?id > `7`].name | [0]".into())),
+            Some("eve".into())
+        );
+        assert_eq!(select(Selector::JmesPath("missing".into())), None);
+
+        assert!(Selector::JsonPath("data".into()).select(BODY).is_err());
+        assert!(Selector::JmesPath("$.data".into())
+            .select("<html>")
+            .is_err());
+    }
+
+    #[test]
+    fn extract_sets_variables() {
+        let table = HashMap::from([
+            ("user_id".to_string(), "$.data[0].id".to_string()),
+            ("names".to_string(), "$.data[*].name".to_string()),
+            ("token".to_string(), "$.token".to_string()),
+        ]);
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+        let mut vars = Vars::new();
+
+        let failures = extract(&conn, "proj", &table, BODY, &mut vars).unwrap();
+        assert_eq!(vars["user_id"], "7");
+        assert_eq!(vars["names"], r#"["bob","eve"]"#);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].script, "extract `token`");
+        assert_eq!(failures[0].message, "`$.token` matched nothing");
+
+        // saved like the variables scripts return, dated for `fresh_for`
+        let (_, saved) = load_variables(&conn, "proj").unwrap();
+        assert_eq!(saved.len(), 2);
+        assert!(variable_updated_at(&conn, "user_id", "proj")
+            .unwrap()
+            .is_some());
+        assert_eq!(variable_updated_at(&conn, "token", "proj").unwrap(), None);
+
+        let failures = extract(&conn, "proj", &table, "not json", &mut Vars::new()).unwrap();
+        assert_eq!(failures.len(), 3);
+    }
+}
@@ -1,11 +1,21 @@
+mod auth;
+mod bench;
 mod cli;
+mod client;
 mod config;
+mod cookies;
+mod data;
+mod extract;
+mod oauth2;
+mod output;
+mod ratelimit;
 mod storage;
 mod runner;
 mod scripting;
+mod signing;
 mod templating;
 
-use crate::cli::{Cli, Command};
+use crate::cli::{BenchArgs, Cli, Command, CookiesAction, RunArgs};
 use clap::Parser;
 use rusqlite::Connection;
 
@@ -14,19 +24,20 @@ fn main() -> anyhow::Result<()> {
 
     match cli.command {
         Command::New { project } => cmd_new(&project)?,
-        Command::Run {
-            env_file,
-            env,
-            project,
-            route,
-            format,
-        } => cmd_run(env_file, env, &project, &route, &format)?,
+        Command::Run(args) => cmd_run(args)?,
+        Command::Bench(args) => cmd_bench(args)?,
         Command::Set {
             label,
             value,
             project,
         } => cmd_set(&label, &value, project.as_deref())?,
         Command::Edit { project } => cmd_edit(&project)?,
+        Command::Cookies { action } => match action {
+            CookiesAction::List { project } => cmd_cookies_list(&project)?,
+            CookiesAction::Clear { project, env_file } => {
+                cmd_cookies_clear(&project, env_file.as_deref())?
+            }
+        },
         Command::Delete { project } => cmd_delete(&project)?,
     }
 
@@ -79,48 +90,210 @@ path   = "/docs"
     Ok(())
 }
 
+/// Load a project file.
+fn load_project(project: &str) -> anyhow::Result<config::ProjectConfig> {
+    let path = config::project_toml_path(project)?;
+    let content = std::fs::read_to_string(&path)?;
+    let mut cfg: config::ProjectConfig = toml::from_str(&content)?;
+    cfg.dir = path.parent().map(|p| p.to_path_buf());
+    Ok(cfg)
+}
+
+/// Variables of every source but `--data`, by precedence.
+struct VarSources {
+    env_file: templating::Vars,
+    global: templating::Vars,
+    project: templating::Vars,
+    cli: templating::Vars,
+}
+
+impl VarSources {
+    fn load(
+        conn: &Connection,
+        project: &str,
+        env_file: Option<&str>,
+        cli_env: Vec<(String, String)>,
+    ) -> anyhow::Result<Self> {
+        let env_file = if let Some(path) = env_file {
+            dotenvy::from_filename_iter(path)?
+                .map(|item| {
+                    let (k, v) = item?;
+                    Ok((k, v))
+                })
+                .collect::<Result<templating::Vars, dotenvy::Error>>()?
+        } else {
+            templating::Vars::new()
+        };
+
+        let (global, project) = storage::load_variables(conn, project)?;
+        let global = global.into_iter().map(|v| (v.label, v.value)).collect();
+        let project = project.into_iter().map(|v| (v.label, v.value)).collect();
+
+        Ok(Self {
+            env_file,
+            global,
+            project,
+            cli: cli_env.into_iter().collect(),
+        })
+    }
+
+    fn merged(&self, data: templating::Vars) -> templating::Vars {
+        templating::merge_vars(
+            self.env_file.clone(),
+            self.global.clone(),
+            self.project.clone(),
+            self.cli.clone(),
+            data,
+        )
+    }
+}
+
 // qwest run ...
-fn cmd_run(
-    env_file: Option<String>,
-    cli_env: Vec<(String, String)>,
-    project: &str,
-    route: &str,
-    format: &str,
-) -> anyhow::Result<()> {
+fn cmd_run(args: RunArgs) -> anyhow::Result<()> {
+    let RunArgs {
+        env_file,
+        env: cli_env,
+        project,
+        route,
+        format,
+        proxy,
+        data,
+        concurrency,
+        output,
+        verbose,
+        quiet,
+        no_color,
+        select,
+        query,
+        save,
+    } = args;
     let conn = open_db()?;
+    let mut cfg = load_project(&project)?;
+
+    // --proxy replaces the project proxy url, keeping its other settings
+    if let Some(url) = proxy {
+        let request_proxies = cfg
+            .requests
+            .iter_mut()
+            .filter_map(|r| r.http.as_mut().and_then(|h| h.proxy.as_mut()));
+        for p in request_proxies {
+            p.url = url.clone();
+        }
+        cfg.api.http.proxy.get_or_insert_with(Default::default).url = url;
+    }
 
-    // Load TOML
-    let path = config::project_toml_path(project)?;
-    let content = std::fs::read_to_string(path)?;
-    let cfg: config::ProjectConfig = toml::from_str(&content)?;
+    let environment = env_file.as_deref().and_then(environment_name);
 
     // Load variables from all sources and merge
-    let env_file_vars = if let Some(path) = env_file {
-        dotenvy::from_filename_iter(path)?
-            .map(|item| {
-                let (k, v) = item?;
-                Ok((k, v))
-            })
-            .collect::<Result<templating::Vars, dotenvy::Error>>()?
-    } else {
-        templating::Vars::new()
+    let sources = VarSources::load(&conn, &project, env_file.as_deref(), cli_env)?;
+
+    // one rate limit for every iteration of --data
+    let limiter = ratelimit::RateLimiter::new(cfg.api.rate_limit);
+    // no colors in pipes and files, nor with NO_COLOR set
+    let color = !no_color && std::env::var_os("NO_COLOR").is_none();
+    let color_stdout = color && std::io::IsTerminal::is_terminal(&std::io::stdout());
+    let color_stderr = color && std::io::IsTerminal::is_terminal(&std::io::stderr());
+    // a selection prints only the selected part of the bodies
+    let selector = match (select, query) {
+        (Some(path), _) => Some(extract::Selector::JsonPath(path)),
+        (None, Some(expr)) => Some(extract::Selector::JmesPath(expr)),
+        (None, None) => None,
+    };
+    let output = match selector {
+        Some(_) => output::Output::Body,
+        None => output,
+    };
+    let printer = output::Printer {
+        output,
+        verbose,
+        quiet,
+        color_stdout,
+        color_stderr,
+        ..Default::default()
+    };
+    let opts = runner::RunOptions {
+        format,
+        printer: printer.clone(),
+        selector,
+        save,
+        environment,
+        concurrency,
+        limiter: Some(std::sync::Arc::new(limiter)),
     };
 
-    let (global_vars, project_vars) = storage::load_variables(&conn, project)?;
-    let global_vars = global_vars
-        .into_iter()
-        .map(|v| (v.label, v.value))
-        .collect();
-    let project_vars = project_vars
-        .into_iter()
-        .map(|v| (v.label, v.value))
-        .collect();
+    let Some(data) = data else {
+        let vars = sources.merged(templating::Vars::new());
+        let report = runner::run_route(&conn, &cfg, &route, vars, &opts)?;
+        return report.ensure_passed(&printer);
+    };
 
-    let cli_vars = cli_env.into_iter().collect();
+    // --data: one run per row, the row's columns above every other variable
+    let rows = data::load(std::path::Path::new(&data))?;
+    let mut report = runner::DataReport::default();
+    for (i, row) in rows.into_iter().enumerate() {
+        printer.log(format!("=== Iteration {} ===", i + 1));
+        report
+            .iterations
+            .push(runner::run_route(&conn, &cfg, &route, sources.merged(row), &opts));
+    }
+    report.ensure_passed(&printer)
+}
 
-    let vars = templating::merge_vars(env_file_vars, global_vars, project_vars, cli_vars);
+// qwest bench my_project my_request -n 1000 -c 10
+fn cmd_bench(args: BenchArgs) -> anyhow::Result<()> {
+    let conn = open_db()?;
+    let cfg = load_project(&args.project)?;
+    let vars = VarSources::load(&conn, &args.project, args.env_file.as_deref(), args.env)?
+        .merged(templating::Vars::new());
+
+    let opts = bench::BenchOptions {
+        requests: args.requests,
+        concurrency: args.concurrency,
+        duration: args.duration,
+        rate: args.rate,
+    };
+    let report = bench::run(&conn, &cfg, &args.route, &vars, &opts)?;
+    report.print();
+    if let Some(path) = args.csv {
+        report.write_csv(std::path::Path::new(&path))?;
+    }
+    Ok(())
+}
 
-    runner::run_route(&conn, &cfg, route, vars, format)
+/// Cookie jars are kept per env file, named after its file name.
+fn environment_name(env_file: &str) -> Option<String> {
+    std::path::Path::new(env_file)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+// qwest cookies list my_project
+fn cmd_cookies_list(project: &str) -> anyhow::Result<()> {
+    let conn = open_db()?;
+    // jars are saved under the api name, not the project file name
+    let cfg = load_project(project)?;
+    for c in cookies::list(&conn, &cfg.api.name)? {
+        let environment = c.environment.as_deref().unwrap_or("-");
+        println!("[{environment}] {}={} (domain={}, path={})", c.name, c.value, c.domain, c.path);
+    }
+    Ok(())
+}
+
+// qwest cookies clear my_project [--env-file .env]
+fn cmd_cookies_clear(project: &str, env_file: Option<&str>) -> anyhow::Result<()> {
+    let conn = open_db()?;
+    let cfg = load_project(project)?;
+    let name = &cfg.api.name;
+    match env_file {
+        Some(file) => {
+            storage::delete_cookie_jar(&conn, name, environment_name(file).as_deref())?;
+        }
+        None => {
+            storage::delete_cookie_jars(&conn, name)?;
+        }
+    }
+    println!("Cleared cookies of project `{project}`");
+    Ok(())
 }
 
 // qwest set variable my_var my_val --project my_project
@@ -159,7 +332,6 @@ fn cmd_delete(project: &str) -> anyhow::Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
     use std::env;
 
     #[test]
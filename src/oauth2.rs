@@ -0,0 +1,262 @@
+use crate::config::{GrantType, OAuth2};
+use crate::storage::{load_token, save_token, OAuthToken};
+use anyhow::Context;
+use reqwest::blocking::Client;
+use rusqlite::Connection;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Tokens expiring within this many seconds are refreshed ahead of time.
+const EXPIRY_MARGIN_SECS: i64 = 30;
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<i64>,
+}
+
+/// Return a valid access token for `cfg` (already rendered).
+///
+/// The cached token is reused while it is fresh, refreshed with its refresh
+/// token once expired, and fetched again from scratch as a last resort.
+pub fn access_token(
+    conn: &Connection,
+    client: &Client,
+    project: &str,
+    cfg: &OAuth2,
+) -> anyhow::Result<String> {
+    let credentials = credentials(cfg);
+    let cached = load_token(conn, project, &cfg.token_url, &cfg.client_id, &credentials)?;
+
+    if let Some(token) = &cached {
+        if !is_expired(token, now()) {
+            return Ok(token.access_token.clone());
+        }
+    }
+
+    let refresh_token = cached
+        .and_then(|t| t.refresh_token)
+        .or_else(|| cfg.refresh_token.clone());
+
+    let token = match (refresh_token, cfg.grant_type) {
+        (Some(previous), GrantType::ClientCredentials) => {
+            // an expired refresh token is not fatal, the client can authenticate again
+            refresh(client, cfg, &previous).or_else(|_| client_credentials(client, cfg))?
+        }
+        (Some(previous), GrantType::RefreshToken) => refresh(client, cfg, &previous)?,
+        (None, GrantType::ClientCredentials) => client_credentials(client, cfg)?,
+        (None, GrantType::RefreshToken) => {
+            anyhow::bail!("oauth2 `refresh_token` grant requires a `refresh_token`")
+        }
+    };
+
+    save_token(
+        conn,
+        project,
+        &cfg.token_url,
+        &cfg.client_id,
+        &credentials,
+        &token,
+    )
+    .context("failed to cache oauth2 token")?;
+    Ok(token.access_token)
+}
+
+/// What else than the client id a cached token was obtained with: its scopes
+/// and a hash of the secret, which is never stored as is.
+fn credentials(cfg: &OAuth2) -> String {
+    let mut scopes = cfg.scopes.clone();
+    scopes.sort();
+    let secret = Sha256::digest(cfg.client_secret.as_deref().unwrap_or_default());
+    format!("{}:{}", scopes.join(" "), hex::encode(secret))
+}
+
+fn client_credentials(client: &Client, cfg: &OAuth2) -> anyhow::Result<OAuthToken> {
+    request_token(
+        client,
+        cfg,
+        vec![("grant_type", "client_credentials".into())],
+        None,
+    )
+}
+
+fn refresh(client: &Client, cfg: &OAuth2, refresh_token: &str) -> anyhow::Result<OAuthToken> {
+    let form = vec![
+        ("grant_type", "refresh_token".into()),
+        ("refresh_token", refresh_token.to_string()),
+    ];
+    request_token(client, cfg, form, Some(refresh_token))
+}
+
+fn request_token(
+    client: &Client,
+    cfg: &OAuth2,
+    mut form: Vec<(&str, String)>,
+    previous_refresh: Option<&str>,
+) -> anyhow::Result<OAuthToken> {
+    form.push(("client_id", cfg.client_id.clone()));
+    if let Some(secret) = &cfg.client_secret {
+        form.push(("client_secret", secret.clone()));
+    }
+    if !cfg.scopes.is_empty() {
+        form.push(("scope", cfg.scopes.join(" ")));
+    }
+
+    let resp = client.post(&cfg.token_url).form(&form).send()?;
+    let status = resp.status();
+    if !status.is_success() {
+        let text = resp.text().unwrap_or_default();
+        anyhow::bail!("oauth2 token request failed ({status}): {text}");
+    }
+    let body: TokenResponse = resp.json().context("invalid oauth2 token response")?;
+
+    Ok(OAuthToken {
+        access_token: body.access_token,
+        // servers may omit the refresh token when it is unchanged
+        refresh_token: body
+            .refresh_token
+            .or_else(|| previous_refresh.map(str::to_string)),
+        expires_at: body.expires_in.map(|secs| now() + secs),
+    })
+}
+
+fn is_expired(token: &OAuthToken, now: i64) -> bool {
+    match token.expires_at {
+        Some(at) => at - EXPIRY_MARGIN_SECS <= now,
+        None => false,
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::init_db;
+    use httpmock::prelude::*;
+
+    fn oauth(server: &MockServer) -> OAuth2 {
+        OAuth2 {
+            token_url: server.url("/token"),
+            client_id: "qwest".into(),
+            client_secret: Some("s3cret".into()),
+            scopes: vec!["read".into()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fetches_and_caches_token() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(POST)
+                .path("/token")
+                .body_contains("grant_type=client_credentials")
+                .body_contains("scope=read");
+            then.status(200)
+                .header("Content-Type", "application/json")
+                .body(r#"{"access_token":"tok-1","expires_in":3600}"#);
+        });
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+        let client = Client::new();
+        let cfg = oauth(&server);
+
+        assert_eq!(access_token(&conn, &client, "p", &cfg).unwrap(), "tok-1");
+        // second call is served from the DB
+        assert_eq!(access_token(&conn, &client, "p", &cfg).unwrap(), "tok-1");
+        m.assert_hits(1);
+    }
+
+    #[test]
+    fn other_scopes_or_secret_fetch_a_new_token() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(POST).path("/token");
+            then.status(200)
+                .header("Content-Type", "application/json")
+                .body(r#"{"access_token":"tok-1","expires_in":3600}"#);
+        });
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+        let client = Client::new();
+        let mut cfg = oauth(&server);
+
+        access_token(&conn, &client, "p", &cfg).unwrap();
+        cfg.scopes.push("write".into());
+        access_token(&conn, &client, "p", &cfg).unwrap();
+        m.assert_hits(2);
+
+        cfg.client_secret = Some("rotated".into());
+        access_token(&conn, &client, "p", &cfg).unwrap();
+        m.assert_hits(3);
+        // the secret itself never reaches the database
+        let stored: String = conn
+            .query_row("SELECT credentials FROM oauth_tokens", [], |row| row.get(0))
+            .unwrap();
+        assert!(!stored.contains("rotated"));
+    }
+
+    #[test]
+    fn refreshes_expired_token() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(POST)
+                .path("/token")
+                .body_contains("grant_type=refresh_token")
+                .body_contains("refresh_token=r-1");
+            then.status(200)
+                .header("Content-Type", "application/json")
+                .body(r#"{"access_token":"tok-2","expires_in":3600}"#);
+        });
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+        let cfg = oauth(&server);
+        let expired = OAuthToken {
+            access_token: "tok-1".into(),
+            refresh_token: Some("r-1".into()),
+            expires_at: Some(now() - 10),
+        };
+        let credentials = credentials(&cfg);
+        save_token(
+            &conn,
+            "p",
+            &cfg.token_url,
+            &cfg.client_id,
+            &credentials,
+            &expired,
+        )
+        .unwrap();
+
+        let token = access_token(&conn, &Client::new(), "p", &cfg).unwrap();
+        assert_eq!(token, "tok-2");
+        m.assert();
+
+        let cached = load_token(&conn, "p", &cfg.token_url, &cfg.client_id, &credentials)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached.refresh_token.as_deref(), Some("r-1"));
+    }
+
+    #[test]
+    fn token_without_expiry_never_expires() {
+        let token = OAuthToken {
+            access_token: "t".into(),
+            refresh_token: None,
+            expires_at: None,
+        };
+        assert!(!is_expired(&token, now()));
+    }
+}
@@ -0,0 +1,499 @@
+use reqwest::blocking::Request;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::StatusCode;
+use std::fmt::Display;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const BLUE: &str = "\x1b[34m";
+const MAGENTA: &str = "\x1b[35m";
+const CYAN: &str = "\x1b[36m";
+
+/// Part of each response printed by `qwest run`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Output {
+    /// Only the body, e.g. to pipe it into `jq`
+    Body,
+    /// Only the headers
+    Headers,
+    /// Only the status code
+    Status,
+    /// Status, headers and body
+    #[default]
+    Full,
+    /// Nothing of the responses
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+type Held = Arc<Mutex<Vec<(Stream, String)>>>;
+
+/// Output held back by a buffered printer, in order, see [`Printer::buffered`].
+#[derive(Debug, Clone, Default)]
+pub struct Buffer {
+    held: Held,
+    /// Where the held output goes once flushed, the terminal when `None`
+    parent: Option<Held>,
+}
+
+/// Prints a run. With the full output everything goes to stdout; with the
+/// others stdout only gets the chosen part of the responses, and the
+/// progress goes to stderr to stay out of pipelines.
+#[derive(Debug, Clone, Default)]
+pub struct Printer {
+    pub output: Output,
+    /// Print the requests sent too, on stderr
+    pub verbose: bool,
+    /// Print nothing at all
+    pub quiet: bool,
+    /// Color the responses, when stdout is a terminal
+    pub color_stdout: bool,
+    /// Color the requests shown by `-v`, when stderr is a terminal
+    pub color_stderr: bool,
+    /// Output held back until [`Printer::flush`]
+    pub buffer: Option<Buffer>,
+}
+
+impl Printer {
+    /// Progress of the run: requests, skipped steps, retries, failures.
+    pub fn log(&self, line: impl Display) {
+        if self.quiet {
+            return;
+        }
+        match self.output {
+            Output::Full => self.write(Stream::Stdout, format!("{line}\n")),
+            _ => self.write(Stream::Stderr, format!("{line}\n")),
+        }
+    }
+
+    /// The request about to be sent, with `-v`.
+    pub fn request(&self, request: &Request) {
+        if self.verbose && !self.quiet {
+            self.write(Stream::Stderr, self.format_request(request));
+        }
+    }
+
+    /// The response received, its body formatted as `format` (json, raw…).
+    pub fn response(&self, status: StatusCode, headers: &HeaderMap, body: &str, format: &str) {
+        if !self.quiet {
+            self.write(
+                Stream::Stdout,
+                self.format_response(status, headers, body, format),
+            );
+        }
+    }
+
+    /// A response whose body is not printed, `note` telling why.
+    pub fn response_without_body(&self, status: StatusCode, headers: &HeaderMap, note: &str) {
+        match self.output {
+            Output::Full => self.response(status, headers, note, "raw"),
+            Output::Body => self.log(note),
+            _ => self.response(status, headers, "", "raw"),
+        }
+    }
+
+    /// A printer holding back its output until [`Printer::flush`], so that
+    /// parallel branches don't print over each other.
+    pub fn buffered(&self) -> Printer {
+        let buffer = Buffer {
+            held: Held::default(),
+            parent: self.buffer.as_ref().map(|b| b.held.clone()),
+        };
+        Printer {
+            buffer: Some(buffer),
+            ..self.clone()
+        }
+    }
+
+    /// Print the output held back, all at once.
+    pub fn flush(&self) {
+        let Some(buffer) = &self.buffer else {
+            return;
+        };
+        let held = std::mem::take(&mut *buffer.held.lock().unwrap());
+        if let Some(parent) = &buffer.parent {
+            parent.lock().unwrap().extend(held);
+            return;
+        }
+        let (mut stdout, mut stderr) = (std::io::stdout().lock(), std::io::stderr().lock());
+        for (stream, text) in held {
+            let _ = match stream {
+                Stream::Stdout => stdout.write_all(text.as_bytes()),
+                Stream::Stderr => stdout
+                    .flush()
+                    .and_then(|_| stderr.write_all(text.as_bytes())),
+            };
+        }
+        let _ = stdout.flush();
+    }
+
+    /// Everything a buffered printer holds back.
+    #[cfg(test)]
+    pub fn held(&self) -> String {
+        let Some(buffer) = &self.buffer else {
+            return String::new();
+        };
+        let held = buffer.held.lock().unwrap();
+        held.iter().map(|(_, text)| text.as_str()).collect()
+    }
+
+    fn write(&self, stream: Stream, text: String) {
+        match &self.buffer {
+            Some(buffer) => buffer.held.lock().unwrap().push((stream, text)),
+            None if stream == Stream::Stdout => print!("{text}"),
+            None => eprint!("{text}"),
+        }
+    }
+
+    /// The request line, headers and body, prefixed with `> ` like curl does.
+    fn format_request(&self, request: &Request) -> String {
+        let mut out = format!(
+            "> {}\n",
+            self.paint(
+                Stream::Stderr,
+                &format!("{} {}", request.method(), request.url()),
+                BOLD
+            )
+        );
+        out += &self.format_headers(request.headers(), "> ", Stream::Stderr);
+        out += ">\n";
+        if let Some(body) = request.body().and_then(|b| b.as_bytes()) {
+            if !body.is_empty() {
+                out += &String::from_utf8_lossy(body);
+                out += "\n";
+            }
+        }
+        out
+    }
+
+    fn format_response(
+        &self,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &str,
+        format: &str,
+    ) -> String {
+        let status_color = match status.as_u16() {
+            200..=299 => GREEN,
+            300..=399 => CYAN,
+            400..=499 => YELLOW,
+            _ => RED,
+        };
+        match self.output {
+            Output::Full => format!(
+                "Status: {}\n--- Response headers ---\n{}--- Response body ---\n{}\n",
+                self.paint(Stream::Stdout, &status.to_string(), status_color),
+                self.format_headers(headers, "", Stream::Stdout),
+                self.format_body(headers, body, format)
+            ),
+            Output::Body => format!("{}\n", self.format_body(headers, body, format)),
+            Output::Headers => self.format_headers(headers, "", Stream::Stdout),
+            Output::Status => format!(
+                "{}\n",
+                self.paint(Stream::Stdout, &status.as_u16().to_string(), status_color)
+            ),
+            Output::None => String::new(),
+        }
+    }
+
+    fn format_headers(&self, headers: &HeaderMap, prefix: &str, stream: Stream) -> String {
+        headers
+            .iter()
+            .map(|(k, v)| {
+                let line = format!("{k}: {}", String::from_utf8_lossy(v.as_bytes()));
+                format!("{prefix}{}\n", self.paint(stream, &line, DIM))
+            })
+            .collect()
+    }
+
+    /// The body formatted as `format`, highlighted as its content type.
+    fn format_body(&self, headers: &HeaderMap, body: &str, format: &str) -> String {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let parsed = serde_json::from_str::<serde_json::Value>(body).ok();
+
+        let text = match (format, &parsed) {
+            // pretty print JSON, otherwise raw
+            ("json", Some(json)) => {
+                serde_json::to_string_pretty(json).unwrap_or_else(|_| body.to_string())
+            }
+            _ => body.to_string(),
+        };
+        if !self.color_stdout {
+            return text;
+        }
+        if parsed.is_some() && (format == "json" || content_type.contains("json")) {
+            highlight_json(&text)
+        } else if format == "html" || content_type.contains("html") || content_type.contains("xml")
+        {
+            highlight_markup(&text)
+        } else {
+            text
+        }
+    }
+
+    /// `text` in `color`, if `stream` gets colors.
+    fn paint(&self, stream: Stream, text: &str, color: &str) -> String {
+        let colored = match stream {
+            Stream::Stdout => self.color_stdout,
+            Stream::Stderr => self.color_stderr,
+        };
+        match colored {
+            true => paint(text, color),
+            false => text.to_string(),
+        }
+    }
+}
+
+fn paint(text: &str, color: &str) -> String {
+    format!("{color}{text}{RESET}")
+}
+
+/// Color the keys, strings, numbers and literals of valid JSON.
+fn highlight_json(json: &str) -> String {
+    let chars: Vec<char> = json.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c == '"' {
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            i = (i + 1).min(chars.len());
+            let token: String = chars[start..i].iter().collect();
+            let is_key = chars[i..].iter().find(|c| !c.is_whitespace()) == Some(&':');
+            out += &paint(&token, if is_key { BLUE } else { GREEN });
+        } else if c == '-' || c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_ascii_digit() || "+-.eE".contains(chars[i])) {
+                i += 1;
+            }
+            out += &paint(&chars[start..i].iter().collect::<String>(), CYAN);
+        } else if c.is_ascii_alphabetic() {
+            while i < chars.len() && chars[i].is_ascii_alphabetic() {
+                i += 1;
+            }
+            out += &paint(&chars[start..i].iter().collect::<String>(), MAGENTA);
+        } else {
+            out.push(c);
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Color the tags, attributes and comments of XML or HTML.
+fn highlight_markup(markup: &str) -> String {
+    let mut out = String::new();
+    let mut rest = markup;
+
+    while let Some(open) = rest.find('<') {
+        out += &rest[..open];
+        rest = &rest[open..];
+        let comment = rest.starts_with("<!--");
+        let end = match comment {
+            true => rest.find("-->").map(|i| i + 3),
+            false => tag_end(rest),
+        }
+        .unwrap_or(rest.len());
+        out += &match comment {
+            true => paint(&rest[..end], DIM),
+            false => highlight_tag(&rest[..end]),
+        };
+        rest = &rest[end..];
+    }
+    out + rest
+}
+
+/// End of the tag `markup` starts with, after its `>`.
+fn tag_end(markup: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in markup.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '>') => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `<name attr="value">`: name in blue, attributes in cyan, values in green.
+fn highlight_tag(tag: &str) -> String {
+    let name_end = tag
+        .char_indices()
+        .skip(1)
+        .find(|(i, c)| c.is_whitespace() || *c == '>' || (*c == '/' && *i > 1))
+        .map_or(tag.len(), |(i, _)| i);
+    let mut out = paint(&tag[..name_end], BLUE);
+
+    let mut rest = &tag[name_end..];
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '"' | '\'' => rest[1..].find(c).map_or(rest.len(), |i| i + 2),
+            c if c.is_whitespace() || c == '=' => c.len_utf8(),
+            '/' | '?' | '>' => c.len_utf8(),
+            _ => rest
+                .find(|c: char| c.is_whitespace() || "=/?>".contains(c))
+                .unwrap_or(rest.len()),
+        };
+        let token = &rest[..len];
+        out += &match c {
+            '"' | '\'' => paint(token, GREEN),
+            '/' | '?' | '>' => paint(token, BLUE),
+            c if c.is_whitespace() || c == '=' => token.to_string(),
+            _ => paint(token, CYAN),
+        };
+        rest = &rest[len..];
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn printer(output: Output, color: bool) -> Printer {
+        Printer {
+            output,
+            color_stdout: color,
+            color_stderr: color,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn responses_print_the_chosen_part() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let print = |output| {
+            printer(output, false).format_response(StatusCode::OK, &headers, r#"{"a":1}"#, "json")
+        };
+
+        assert_eq!(print(Output::Body), "{\n  \"a\": 1\n}\n");
+        assert_eq!(print(Output::Headers), "content-type: application/json\n");
+        assert_eq!(print(Output::Status), "200\n");
+        assert_eq!(print(Output::None), "");
+        assert_eq!(
+            print(Output::Full),
+            "Status: 200 OK\n--- Response headers ---\ncontent-type: application/json\n\
+             --- Response body ---\n{\n  \"a\": 1\n}\n"
+        );
+        let raw = printer(Output::Body, false).format_body(&headers, "not json", "json");
+        assert_eq!(raw, "not json");
+    }
+
+    #[test]
+    fn verbose_requests_look_like_curl() {
+        let request = reqwest::blocking::Client::new()
+            .post("http://localhost/items")
+            .header("X-Id", "1")
+            .body("hello")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            printer(Output::Full, false).format_request(&request),
+            "> POST http://localhost/items\n> x-id: 1\n>\nhello\n"
+        );
+
+        // stderr in a terminal, stdout piped
+        let stderr_only = Printer {
+            color_stderr: true,
+            ..printer(Output::Body, false)
+        };
+        assert!(stderr_only
+            .format_request(&request)
+            .starts_with(&format!("> {BOLD}POST http://localhost/items{RESET}\n")));
+        let headers = request.headers();
+        assert_eq!(
+            stderr_only.format_response(StatusCode::OK, headers, "1", "json"),
+            "1\n"
+        );
+    }
+
+    #[test]
+    fn colors_follow_the_status_and_content_type() {
+        let color = printer(Output::Full, true);
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+
+        let status = color.format_response(StatusCode::NOT_FOUND, &headers, "", "raw");
+        assert!(status.starts_with(&format!("Status: {YELLOW}404 Not Found{RESET}")));
+        assert!(status.contains(&format!("{DIM}content-type: text/html{RESET}")));
+
+        let html = color.format_body(&headers, "<a>hi</a>", "raw");
+        assert_eq!(
+            html,
+            format!("{BLUE}<a{RESET}{BLUE}>{RESET}hi{BLUE}</a{RESET}{BLUE}>{RESET}")
+        );
+
+        // only valid JSON is highlighted
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert_eq!(color.format_body(&headers, "{oops", "raw"), "{oops");
+    }
+
+    #[test]
+    fn highlight_json_tokens() {
+        let json = r#"{"k\"ey": ["v", -1.5e3, true, null]}"#;
+        assert_eq!(
+            highlight_json(json),
+            format!(
+                "{{{BLUE}\"k\\\"ey\"{RESET}: [{GREEN}\"v\"{RESET}, {CYAN}-1.5e3{RESET}, \
+                 {MAGENTA}true{RESET}, {MAGENTA}null{RESET}]}}"
+            )
+        );
+    }
+
+    #[test]
+    fn highlight_markup_tags_attributes_and_comments() {
+        let xml = r#"<?xml version="1.0"?><!-- note --><item id='a>b' hidden/>text"#;
+        assert_eq!(
+            highlight_markup(xml),
+            format!(
+                "{BLUE}<?xml{RESET} {CYAN}version{RESET}={GREEN}\"1.0\"{RESET}{BLUE}?{RESET}\
+                 {BLUE}>{RESET}{DIM}<!-- note -->{RESET}{BLUE}<item{RESET} {CYAN}id{RESET}=\
+                 {GREEN}'a>b'{RESET} {CYAN}hidden{RESET}{BLUE}/{RESET}{BLUE}>{RESET}text"
+            )
+        );
+    }
+
+    #[test]
+    fn buffered_printers_hold_their_output() {
+        let outer = printer(Output::Body, false).buffered();
+        let inner = outer.buffered();
+
+        inner.log("==> a");
+        outer.log("==> b");
+
+        inner.flush();
+        inner.flush();
+
+        let held = outer.buffer.unwrap().held.lock().unwrap().clone();
+        assert_eq!(
+            held,
+            vec![
+                (Stream::Stderr, "==> b\n".to_string()),
+                (Stream::Stderr, "==> a\n".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use crate::config::RateLimit;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Spaces out the requests of a run evenly to stay under a rate limit,
+/// shared by every thread sending them.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    interval: Option<Duration>,
+    /// When the next request may be sent
+    next: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    /// Without a limit, `wait` returns at once.
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        RateLimiter {
+            interval: limit.map(|l| l.interval()),
+            next: Mutex::default(),
+        }
+    }
+
+    /// Block until a request may be sent, and book its slot.
+    pub fn wait(&self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let now = Instant::now();
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = next.map_or(now, |next| next.max(now));
+            *next = Some(slot + interval);
+            slot
+        };
+        std::thread::sleep(slot - now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_spaced_across_threads() {
+        let limiter = RateLimiter::new(Some(RateLimit {
+            requests: 20,
+            period: Duration::from_secs(1),
+        }));
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..3 {
+                scope.spawn(|| {
+                    limiter.wait();
+                    limiter.wait();
+                });
+            }
+        });
+        // the first of the 6 requests is sent at once
+        assert!(start.elapsed() >= Duration::from_millis(250));
+
+        let unlimited = RateLimiter::new(None);
+        let start = Instant::now();
+        for _ in 0..100 {
+            unlimited.wait();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+}
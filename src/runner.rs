@@ -1,121 +1,1092 @@
-use crate::config::{ProjectConfig, Request};
-use crate::scripting::{run_scripts_after, run_scripts_before};
+use crate::auth;
+use crate::client;
+use crate::config::{
+    Api, Auth, OnFailure, ParallelGroup, ProjectConfig, Request, Retry, Script, Step, StepTable,
+};
+use crate::cookies::{self, Jar};
+use crate::extract::{self, Selector};
+use crate::output::Printer;
+use crate::ratelimit::RateLimiter;
+use crate::scripting::{
+    eval_condition, eval_list, run_scripts_after, run_scripts_before, AssertionFailure, Modules,
+    ScriptEnv, ScriptHost, ScriptRequest, ScriptResponse,
+};
+use crate::storage::{self, Db};
 use crate::templating::{render_placeholders, Vars};
 use anyhow::Context;
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::RETRY_AFTER;
 use rusqlite::Connection;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Attempts of an `until` step without `max_attempts`.
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+/// Wait between the attempts of an `until` step without `interval`.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many `goto:` jumps a scenario may take, so that a step failing
+/// for good doesn't loop forever.
+const MAX_JUMPS: usize = 100;
+
+/// Longest `Retry-After` honored, so that a server can't stall a run.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// How deep `run_request` calls may nest, so that a request running itself
+/// fails instead of overflowing the stack.
+const MAX_NESTED_REQUESTS: usize = 8;
+
+/// Options of `qwest run` that do not come from the project file.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Output format: json, html, raw…
+    pub format: String,
+    /// What is printed of the run
+    pub printer: Printer,
+    /// Print only this part of the responses
+    pub selector: Option<Selector>,
+    /// Write the response body of the route's request, or of the last step
+    /// of the scenario, to this file instead, with placeholders
+    pub save: Option<String>,
+    /// Name of the env file, scopes the persisted cookie jar
+    pub environment: Option<String>,
+    /// Branches of a parallel group run at once, all of them if unset
+    pub concurrency: Option<usize>,
+    /// Rate limit shared with other runs, e.g. the iterations of `--data`.
+    /// Unset, every run follows `api.rate_limit` on its own.
+    pub limiter: Option<Arc<RateLimiter>>,
+}
+
+/// Result of one request of a run.
+#[derive(Debug, Clone, Default)]
+pub struct RequestOutcome {
+    pub name: String,
+    pub failures: Vec<AssertionFailure>,
+    /// Error of a request whose step continued past it
+    pub error: Option<String>,
+}
+
+/// Results of every request run by a route or scenario.
+#[derive(Debug, Clone, Default)]
+pub struct RunReport {
+    pub requests: Vec<RequestOutcome>,
+}
+
+impl RunReport {
+    pub fn failure_count(&self) -> usize {
+        self.requests.iter().map(|r| r.failures.len()).sum()
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.requests.iter().filter(|r| r.error.is_some()).count()
+    }
+
+    /// Print the failed assertions and requests, and fail if there is any.
+    pub fn ensure_passed(&self, printer: &Printer) -> anyhow::Result<()> {
+        let (count, errors) = (self.failure_count(), self.error_count());
+
+        if count > 0 {
+            printer.log("--- Assertion failures ---");
+            for request in &self.requests {
+                for f in &request.failures {
+                    printer.log(format!("{} > {}: {}", request.name, f.script, f.message));
+                }
+            }
+        }
+        if errors > 0 {
+            printer.log("--- Failed requests ---");
+            for request in &self.requests {
+                if let Some(error) = &request.error {
+                    printer.log(format!("{}: {}", request.name, error));
+                }
+            }
+        }
+
+        match (count, errors) {
+            (0, 0) => Ok(()),
+            (count, 0) => anyhow::bail!("{count} assertion(s) failed"),
+            (0, errors) => anyhow::bail!("{errors} request(s) failed"),
+            (count, errors) => {
+                anyhow::bail!("{count} assertion(s) and {errors} request(s) failed")
+            }
+        }
+    }
+}
+
+/// Results of a `--data` run, one per row.
+#[derive(Debug, Default)]
+pub struct DataReport {
+    pub iterations: Vec<anyhow::Result<RunReport>>,
+}
+
+impl DataReport {
+    /// Print the result of every iteration, and fail if any failed.
+    pub fn ensure_passed(&self, printer: &Printer) -> anyhow::Result<()> {
+        let mut failed = 0;
+        for (i, iteration) in self.iterations.iter().enumerate() {
+            printer.log(format!("--- Iteration {} ---", i + 1));
+            let result = match iteration {
+                Ok(report) => report.ensure_passed(printer),
+                Err(e) => Err(anyhow::anyhow!("{e:#}")),
+            };
+            match result {
+                Ok(()) => printer.log("passed"),
+                Err(e) => {
+                    failed += 1;
+                    printer.log(e);
+                }
+            }
+        }
+
+        let total = self.iterations.len();
+        if failed > 0 {
+            anyhow::bail!("{failed} of {total} iteration(s) failed");
+        }
+        Ok(())
+    }
+}
+
+/// State shared by the requests of a run, including the ones scripts run.
+struct RunContext {
+    db: Db,
+    cfg: ProjectConfig,
+    opts: RunOptions,
+    client: Client,
+    jar: Jar,
+    modules: Modules,
+    /// Outcomes of the requests run by scripts, not reported yet
+    nested: RefCell<Vec<RequestOutcome>>,
+    /// Current `run_request` nesting
+    depth: Cell<usize>,
+    /// Shared with the parallel branches of the run
+    ran: Arc<Ran>,
+    limiter: Arc<RateLimiter>,
+}
+
+/// What the requests of a run, in every branch, know of each other.
+#[derive(Default)]
+struct Ran {
+    /// When each request last ran, to skip the `needs` already met
+    at: Mutex<HashMap<String, Instant>>,
+    /// Held while the needs of a step run, so that branches needing the
+    /// same request run it once
+    needs: Mutex<()>,
+}
+
+impl RunContext {
+    fn new(
+        db: Db,
+        cfg: &ProjectConfig,
+        opts: &RunOptions,
+        client: &Client,
+        jar: &Jar,
+        ran: Arc<Ran>,
+    ) -> anyhow::Result<Rc<Self>> {
+        Ok(Rc::new(RunContext {
+            db,
+            cfg: cfg.clone(),
+            opts: opts.clone(),
+            client: client.clone(),
+            jar: jar.clone(),
+            modules: Modules::load(cfg.dir.as_deref(), &cfg.api.scripts.modules)?,
+            nested: RefCell::default(),
+            depth: Cell::default(),
+            ran,
+            limiter: opts
+                .limiter
+                .clone()
+                .unwrap_or_else(|| Arc::new(RateLimiter::new(cfg.api.rate_limit))),
+        }))
+    }
+}
+
+/// Serves the requests of the scripts of a run.
+struct Host(Rc<RunContext>);
+
+impl ScriptHost for Host {
+    fn send(&self, request: ScriptRequest, vars: &Vars) -> anyhow::Result<ScriptResponse> {
+        let ctx = &self.0;
+        let mut url = render_placeholders(&request.url, vars)?;
+        if url.starts_with('/') {
+            url = format!("{}{url}", ctx.cfg.api.base_url);
+        }
+        let mut headers = rhai::Map::new();
+        for (k, v) in request.headers {
+            headers.insert(k, render_placeholders(&v.to_string(), vars)?.into());
+        }
+        let request = ScriptRequest {
+            url,
+            headers,
+            body: render_placeholders(&request.body, vars)?,
+            ..request
+        };
+
+        let request = request_builder(&ctx.client, request)?.build()?;
+        ctx.limiter.wait();
+        ctx.opts.printer.request(&request);
+        let resp = ctx.client.execute(request)?;
+        let (status, headers) = (resp.status().as_u16(), resp.headers().clone());
+        Ok(ScriptResponse::new(status, &headers, resp.text()?))
+    }
+
+    fn run_request(&self, name: &str, vars: &Vars) -> anyhow::Result<ScriptResponse> {
+        let ctx = &self.0;
+        let request = find_request(&ctx.cfg, name)?;
+
+        let depth = ctx.depth.get();
+        if depth >= MAX_NESTED_REQUESTS {
+            anyhow::bail!("run_request nested more than {MAX_NESTED_REQUESTS} times");
+        }
+        ctx.depth.set(depth + 1);
+        let result = run_single_request(ctx, request, &mut vars.clone(), false)
+            .with_context(|| format!("request `{name}` failed"));
+        ctx.depth.set(depth);
+
+        let (outcome, response, _) = result?;
+        ctx.nested.borrow_mut().push(outcome);
+        Ok(response)
+    }
+}
 
 pub fn run_route(
     conn: &Connection,
     cfg: &ProjectConfig,
     route: &str,
     mut vars: Vars,
-    format: &str,
-) -> anyhow::Result<()> {
+    opts: &RunOptions,
+) -> anyhow::Result<RunReport> {
     let api = &cfg.api;
 
-    let request_names = if let Some(req) = cfg.requests.iter().find(|r| r.name == route) {
-        vec![req.name.clone()]
+    let steps: Vec<Step> = if let Some(req) = cfg.requests.iter().find(|r| r.name == route) {
+        vec![Step::from(req.name.as_str())]
     } else if let Some(seq) = api.scenarios.get(route) {
         seq.clone()
     } else {
         anyhow::bail!("unknown route or scenario `{route}`");
     };
 
-    let client = Client::new();
-    for req_name in request_names {
-        let req_cfg = cfg
-            .requests
-            .iter()
-            .find(|r| r.name == req_name)
-            .ok_or_else(|| anyhow::anyhow!("request `{req_name}` not found"))?;
+    // one jar for the whole scenario, shared by every client
+    let environment = opts.environment.as_deref();
+    let jar = if api.persist_cookies {
+        cookies::load(conn, &api.name, environment)?
+    } else {
+        Jar::default()
+    };
 
-        run_single_request(conn, &client, api, req_cfg, &mut vars, format)?;
+    // parallel branches share the limit of the run
+    let mut opts = opts.clone();
+    opts.limiter
+        .get_or_insert_with(|| Arc::new(RateLimiter::new(api.rate_limit)));
+
+    if opts.save.is_some() && matches!(steps.last(), Some(Step::Parallel(_))) {
+        anyhow::bail!("`--save` has nothing to save: the last step is a parallel group");
     }
 
-    Ok(())
+    let client = client::build(&api.http, &jar)?;
+    // scripts hold on to the context for as long as they like, so it can't
+    // borrow `conn`: the run sends its queries back to this thread instead
+    let result = storage::serve(conn, |db| {
+        let ctx = RunContext::new(db, cfg, &opts, &client, &jar, Arc::default())?;
+        let mut report = RunReport::default();
+        let keep_last = opts.save.is_some();
+        let kept = run_steps(&ctx, &steps, &mut vars, &mut report, &mut None, keep_last)?;
+        if let Some(path) = &opts.save {
+            let body = kept.ok_or_else(|| {
+                anyhow::anyhow!("`--save` has nothing to save: the last step received no response")
+            })?;
+            let path = render_placeholders(path, &vars)?;
+            let size = save_body(&mut body.as_slice(), Path::new(&path))
+                .with_context(|| format!("could not save the response to `{path}`"))?;
+            opts.printer.log(format!("saved {size} bytes to {path}"));
+        }
+        Ok(report)
+    });
+
+    // keep the cookies received before a failure too
+    if api.persist_cookies {
+        cookies::save(conn, &api.name, environment, &jar)?;
+    }
+
+    result
 }
 
-fn run_single_request(
-    conn: &Connection,
-    client: &Client,
-    api: &crate::config::Api,
+/// Run `steps` in order, `last` being the latest response. With
+/// `keep_last`, returns the body of the last response the last step
+/// received, if it ran.
+fn run_steps(
+    ctx: &Rc<RunContext>,
+    steps: &[Step],
+    vars: &mut Vars,
+    report: &mut RunReport,
+    last: &mut Option<ScriptResponse>,
+    keep_last: bool,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut kept = None;
+    let mut jumps = 0;
+    let mut next = 0;
+
+    while let Some(step) = steps.get(next) {
+        next += 1;
+        let keep = keep_last && next == steps.len();
+
+        let step = match step {
+            Step::Parallel(group) => {
+                if let Some(response) = run_parallel(ctx, group, vars, report)? {
+                    *last = Some(response);
+                }
+                continue;
+            }
+            other => other.table().expect("request steps have a table"),
+        };
+        let step = &step;
+
+        if !should_run(step, vars, last.as_ref())? {
+            ctx.opts.printer.log(format!("==> {} (skipped)", step.name()));
+            continue;
+        }
+
+        let req_cfg = find_request(&ctx.cfg, &step.request)?;
+
+        let items = match &step.for_each {
+            Some(expr) => eval_list(expr, vars, last.as_ref())
+                .with_context(|| format!("for_each of step `{}` failed", step.name()))?
+                .into_iter()
+                .map(Some)
+                .collect(),
+            None => vec![None],
+        };
+        {
+            let _needs = ctx.ran.needs.lock().unwrap();
+            run_needs(ctx, req_cfg, vars, report)?;
+        }
+
+        let stops = matches!(
+            step.on_failure,
+            Some(OnFailure::Abort | OnFailure::Goto(_))
+        );
+        // the item variable is only set for the loop
+        let shadowed = step
+            .for_each
+            .as_ref()
+            .map(|_| vars.get(step.item_var()).cloned());
+        let mut failed = false;
+        for item in items {
+            if let Some(item) = item {
+                vars.insert(step.item_var().to_string(), item);
+            }
+            if let Some(delay) = step.delay_ms {
+                std::thread::sleep(Duration::from_millis(delay));
+            }
+            let (step_failed, body) = run_attempts(ctx, step, req_cfg, vars, report, last, keep)?;
+            if body.is_some() {
+                kept = body;
+            }
+            if step_failed {
+                failed = true;
+                if stops {
+                    break;
+                }
+            }
+        }
+        match shadowed {
+            Some(Some(previous)) => {
+                vars.insert(step.item_var().to_string(), previous);
+            }
+            Some(None) => {
+                vars.remove(step.item_var());
+            }
+            None => {}
+        }
+        if !failed {
+            continue;
+        }
+
+        match &step.on_failure {
+            None | Some(OnFailure::Continue) => {}
+            Some(OnFailure::Abort) => {
+                ctx.opts.printer.log(format!("==> {} failed, aborting", step.name()));
+                break;
+            }
+            Some(OnFailure::Goto(target)) => {
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    anyhow::bail!("scenario jumped more than {MAX_JUMPS} times, giving up");
+                }
+                next = steps
+                    .iter()
+                    .position(|s| s.name() == Some(target))
+                    .ok_or_else(|| anyhow::anyhow!("unknown step `{target}` in goto"))?;
+            }
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Run the branches of a parallel group on their own threads, at most
+/// `--concurrency` at once, then merge the variables they changed: when
+/// several branches set one, the branch declared last wins. So does its
+/// last response, which is returned.
+fn run_parallel(
+    ctx: &Rc<RunContext>,
+    group: &ParallelGroup,
+    vars: &mut Vars,
+    report: &mut RunReport,
+) -> anyhow::Result<Option<ScriptResponse>> {
+    let branches = &group.parallel;
+    if branches.is_empty() {
+        return Ok(None);
+    }
+    let workers = ctx
+        .opts
+        .concurrency
+        .unwrap_or(branches.len())
+        .clamp(1, branches.len());
+
+    let shared = Shared {
+        db: &ctx.db,
+        cfg: &ctx.cfg,
+        opts: &ctx.opts,
+        client: &ctx.client,
+        jar: &ctx.jar,
+        ran: &ctx.ran,
+    };
+    let before: &Vars = vars;
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<BranchResult>>> =
+        branches.iter().map(|_| Mutex::default()).collect();
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(branch) = branches.get(i) else {
+                    break;
+                };
+                let result = run_branch(&shared, branch.steps(), before);
+                *results[i].lock().unwrap() = Some(result);
+            });
+        }
+    });
+
+    let mut changed = Vars::new();
+    let mut last = None;
+    for result in results {
+        let (branch_vars, branch_report, branch_last) = result
+            .into_inner()
+            .expect("branch thread panicked")
+            .expect("every branch ran")?;
+        report.requests.extend(branch_report.requests);
+        last = branch_last.map(ScriptResponse::from).or(last);
+        changed.extend(
+            branch_vars
+                .into_iter()
+                .filter(|(k, v)| vars.get(k) != Some(v)),
+        );
+    }
+    vars.extend(changed);
+
+    Ok(last)
+}
+
+/// The parts of a run context its parallel branches share.
+struct Shared<'a> {
+    db: &'a Db,
+    cfg: &'a ProjectConfig,
+    opts: &'a RunOptions,
+    client: &'a Client,
+    jar: &'a Jar,
+    ran: &'a Arc<Ran>,
+}
+
+type BranchResult = anyhow::Result<(Vars, RunReport, Option<SentResponse>)>;
+
+/// A response of a branch: script values can't leave the thread they were
+/// made on.
+struct SentResponse {
+    status: i64,
+    headers: Vec<(String, String)>,
+    text: String,
+}
+
+impl From<ScriptResponse> for SentResponse {
+    fn from(response: ScriptResponse) -> Self {
+        let headers = response
+            .headers
+            .into_iter()
+            .map(|(k, v)| (k.into(), v.to_string()))
+            .collect();
+        SentResponse {
+            status: response.status,
+            headers,
+            text: response.text,
+        }
+    }
+}
+
+impl From<SentResponse> for ScriptResponse {
+    fn from(response: SentResponse) -> Self {
+        let headers = response
+            .headers
+            .into_iter()
+            .map(|(k, v)| (k.into(), v.into()))
+            .collect();
+        ScriptResponse {
+            status: response.status,
+            headers,
+            text: response.text,
+        }
+    }
+}
+
+/// Run the steps of a branch with a copy of the variables, on a context
+/// of its own sharing the requests already run. Its output is printed
+/// once it is done, in one piece.
+fn run_branch(shared: &Shared, steps: &[Step], vars: &Vars) -> BranchResult {
+    let opts = RunOptions {
+        printer: shared.opts.printer.buffered(),
+        ..shared.opts.clone()
+    };
+    let ctx = RunContext::new(
+        shared.db.clone(),
+        shared.cfg,
+        &opts,
+        shared.client,
+        shared.jar,
+        shared.ran.clone(),
+    )?;
+    let mut vars = vars.clone();
+    let mut report = RunReport::default();
+    let mut last = None;
+    let result = run_steps(&ctx, steps, &mut vars, &mut report, &mut last, false);
+    opts.printer.flush();
+    result.map(|_| (vars, report, last.map(SentResponse::from)))
+}
+
+/// Run the request of a step, again until its `until` holds. Returns
+/// whether it failed, only the checks of the last attempt counting, and
+/// with `keep` the body of the last response.
+fn run_attempts(
+    ctx: &Rc<RunContext>,
+    step: &StepTable,
+    request: &Request,
+    vars: &mut Vars,
+    report: &mut RunReport,
+    last: &mut Option<ScriptResponse>,
+    keep: bool,
+) -> anyhow::Result<(bool, Option<Vec<u8>>)> {
+    let max_attempts = match &step.until {
+        Some(_) => step.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+        None => 1,
+    };
+    let interval = step.interval.map_or(DEFAULT_INTERVAL, |i| i.0);
+
+    let mut attempt = 0;
+    let mut kept = None;
+    loop {
+        attempt += 1;
+        let result = run_single_request(ctx, request, vars, keep)
+            .with_context(|| format!("request `{}` failed", request.name));
+        report.requests.extend(ctx.nested.take());
+
+        let (mut outcome, response, body) = match result {
+            Ok(done) => done,
+            Err(e) if matches!(step.on_failure, Some(OnFailure::Continue | OnFailure::Goto(_))) => {
+                report.requests.push(RequestOutcome {
+                    name: request.name.clone(),
+                    error: Some(format!("{e:#}")),
+                    ..Default::default()
+                });
+                return Ok((true, kept));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let done = match &step.until {
+            Some(expr) => eval_condition(expr, vars, Some(&response))
+                .with_context(|| format!("until of step `{}` failed", step.name()))?,
+            None => true,
+        };
+        let final_attempt = done || attempt == max_attempts;
+        if !final_attempt {
+            outcome.failures.clear();
+        } else if !done {
+            outcome.failures.push(AssertionFailure {
+                script: "until".into(),
+                message: format!(
+                    "`{}` still false after {max_attempts} attempt(s)",
+                    step.until.as_deref().unwrap_or_default()
+                ),
+            });
+        }
+
+        let failed = !outcome.failures.is_empty();
+        report.requests.push(outcome);
+        *last = Some(response);
+        kept = body.or(kept);
+        if final_attempt {
+            return Ok((failed, kept));
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Run the requests `request` needs, and theirs first, skipping the fresh
+/// ones.
+fn run_needs(
+    ctx: &Rc<RunContext>,
     request: &Request,
     vars: &mut Vars,
-    format: &str,
+    report: &mut RunReport,
 ) -> anyhow::Result<()> {
-    println!("==> {}", request.name);
+    if request.needs.is_empty() {
+        return Ok(());
+    }
+    check_needs(&ctx.cfg.requests, request, &mut Vec::new(), &mut HashSet::new())?;
 
-    // scripts BEFORE
-    let scripts = &request.scripts;
-    run_scripts_before(conn, &api.name, scripts, vars)?;
+    for name in &request.needs {
+        let need = find_request(&ctx.cfg, name)?;
+        if is_fresh(ctx, need, vars)? {
+            ctx.opts.printer.log(format!("==> {name} (fresh)"));
+            continue;
+        }
+        run_needs(ctx, need, vars, report)?;
 
-    let url = render_placeholders(&format!("{}{}", api.base_url, request.path), vars)?;
+        let result = run_single_request(ctx, need, vars, false)
+            .with_context(|| format!("request `{name}` needed by `{}` failed", request.name));
+        report.requests.extend(ctx.nested.take());
+        report.requests.push(result?.0);
+    }
+    Ok(())
+}
 
-    let mut builder = client.request(request.method.parse()?, &url);
+/// Fail if a request `request` needs is missing or needs itself, through
+/// `path`, the requests needing `request`.
+fn check_needs<'a>(
+    requests: &'a [Request],
+    request: &'a Request,
+    path: &mut Vec<&'a str>,
+    checked: &mut HashSet<&'a str>,
+) -> anyhow::Result<()> {
+    if checked.contains(request.name.as_str()) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|name| *name == request.name) {
+        let cycle = path[start..].join(" -> ");
+        anyhow::bail!("requests need each other: {cycle} -> {}", request.name);
+    }
 
-    if let Some(h) = &request.headers {
-        let rendered = render_placeholders(h, vars)?;
-        let map: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&rendered)
-            .context("invalid JSON in headers")?;
-        for (k, v) in map {
-            builder = builder.header(k, v.as_str().unwrap_or(&v.to_string()));
+    path.push(&request.name);
+    for name in &request.needs {
+        let need = requests.iter().find(|r| &r.name == name).ok_or_else(|| {
+            anyhow::anyhow!("request `{name}` needed by `{}` not found", request.name)
+        })?;
+        check_needs(requests, need, path, checked)?;
+    }
+    path.pop();
+    checked.insert(&request.name);
+    Ok(())
+}
+
+/// Whether a needed request can be skipped: it ran already in this run, or
+/// the variables it provides are set, within `fresh_for` in both cases.
+/// With `fresh_for`, variables only count when a script saved them.
+fn is_fresh(ctx: &RunContext, request: &Request, vars: &Vars) -> anyhow::Result<bool> {
+    let ttl = request.fresh_for.map(|f| f.0);
+    if let Some(ran) = ctx.ran.at.lock().unwrap().get(&request.name) {
+        return Ok(ttl.is_none_or(|ttl| ran.elapsed() < ttl));
+    }
+
+    let provided = !request.provides.is_empty()
+        && request
+            .provides
+            .iter()
+            .all(|label| vars.get(label).is_some_and(|v| !v.is_empty()));
+    let Some(ttl) = ttl else {
+        return Ok(provided);
+    };
+    if !provided {
+        return Ok(false);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    for label in &request.provides {
+        let (label, project) = (label.clone(), ctx.cfg.api.name.clone());
+        let updated_at = ctx
+            .db
+            .call(move |conn| storage::variable_updated_at(conn, &label, &project))?;
+        match updated_at {
+            Some(at) if now.saturating_sub(at) < ttl.as_secs() as i64 => {}
+            _ => return Ok(false),
         }
     }
+    Ok(true)
+}
 
-    if let Some(b) = &request.body {
-        let rendered = render_placeholders(b, vars)?;
-        builder = builder.body(rendered);
+fn find_request<'a>(cfg: &'a ProjectConfig, name: &str) -> anyhow::Result<&'a Request> {
+    cfg.requests
+        .iter()
+        .find(|r| r.name == name)
+        .ok_or_else(|| anyhow::anyhow!("request `{name}` not found"))
+}
+
+/// Whether a step passes its `skip_unless` and `if` conditions.
+fn should_run(
+    step: &StepTable,
+    vars: &Vars,
+    last: Option<&ScriptResponse>,
+) -> anyhow::Result<bool> {
+    if let Some(var) = &step.skip_unless {
+        if vars.get(var).is_none_or(|v| v.is_empty()) {
+            return Ok(false);
+        }
+    }
+    match &step.condition {
+        Some(expr) => eval_condition(expr, vars, last)
+            .with_context(|| format!("condition of step `{}` failed", step.name())),
+        None => Ok(true),
     }
+}
+
+/// Send a request between its scripts. With `keep`, its body is returned
+/// for `--save` instead of being printed, and its `save_to` is ignored.
+fn run_single_request(
+    ctx: &Rc<RunContext>,
+    request: &Request,
+    vars: &mut Vars,
+    keep: bool,
+) -> anyhow::Result<(RequestOutcome, ScriptResponse, Option<Vec<u8>>)> {
+    let (db, api) = (&ctx.db, &ctx.cfg.api);
+    let printer = &ctx.opts.printer;
+    let env = ScriptEnv {
+        modules: ctx.modules.clone(),
+        host: Some(Rc::new(Host(ctx.clone()))),
+        printer: printer.clone(),
+    };
+    printer.log(format!("==> {}", request.name));
+
+    // requests overriding http options get their own client
+    let dedicated;
+    let client = match &request.http {
+        Some(http) => {
+            dedicated = client::build(&api.http.merged(http), &ctx.jar)?;
+            &dedicated
+        }
+        None => &ctx.client,
+    };
+
+    // project hooks run around the request's own scripts
+    let scripts: Vec<Script> = api
+        .scripts
+        .hooks
+        .iter()
+        .filter(|s| s.before)
+        .chain(&request.scripts)
+        .chain(api.scripts.hooks.iter().filter(|s| !s.before))
+        .cloned()
+        .collect();
+
+    // scripts BEFORE, they can edit the rendered request
+    let initial = render_request(api, request, vars, false)?;
+    let mut prepared = initial.clone();
+    let mut failures = run_scripts_before(db, &api.name, &scripts, vars, &mut prepared, &env)?;
+    // variables returned by the scripts apply to what they left untouched
+    let prepared = prepared.merge_untouched(&initial, render_request(api, request, vars, true)?);
+
+    let builder = request_builder(client, prepared)?;
 
-    let resp = builder.send()?;
+    let auth = match request.auth.as_ref().or(api.auth.as_ref()) {
+        Some(a) => {
+            let (client, project, auth) =
+                (client.clone(), api.name.clone(), auth::render(a, vars)?);
+            Some(db.call(move |conn| auth::resolve(conn, &client, &project, auth))?)
+        }
+        None => None,
+    };
+    let retry = match (&api.retry, &request.retry) {
+        (Some(api), Some(own)) => Some(api.merged(own)),
+        (api, own) => own.clone().or_else(|| api.clone()),
+    };
+    let save_to = match request.save_to.as_deref().filter(|_| !keep) {
+        Some(path) => Some(render_placeholders(path, vars)?),
+        None => None,
+    };
+    let mut resp = send_with_retry(
+        client,
+        builder.build()?,
+        auth.as_ref(),
+        retry.as_ref(),
+        &ctx.limiter,
+        printer,
+    )?;
     let status = resp.status();
     let headers = resp.headers().clone();
-    let text = resp.text()?;
 
-    println!("Status: {}", status);
-    println!("--- Response headers ---");
-    for (k, v) in headers.iter() {
-        println!("{}: {:?}", k, v);
-    }
-
-    println!("--- Response body ---");
-    match format {
-        "json" => {
-            // Try to pretty print JSON, otherwise raw
-            if let Ok(json) = serde_json::from_str::<serde_json::Value>(&text) {
-                println!("{}", serde_json::to_string_pretty(&json)?);
-            } else {
-                println!("{}", text);
-            }
+    // saved bodies are streamed to the file and not kept, binary ones not printed
+    let mut kept = None;
+    let (text, not_printed) = match &save_to {
+        Some(path) => {
+            let size = save_body(&mut resp, Path::new(path))
+                .with_context(|| format!("could not save the response to `{path}`"))?;
+            vars.insert("response_file".to_string(), path.clone());
+            (String::new(), Some(format!("(saved {size} bytes to {path})")))
         }
-        "html" => {
-            // Maybe just print raw; formatting can be added later
-            println!("{}", text);
+        None if keep => {
+            vars.remove("response_file");
+            let body = resp.bytes()?.to_vec();
+            let note = format!("(body of {} bytes kept for --save)", body.len());
+            let text = String::from_utf8_lossy(&body).into_owned();
+            kept = Some(body);
+            (text, Some(note))
         }
-        _ => {
-            println!("{}", text);
+        None => {
+            vars.remove("response_file");
+            match String::from_utf8(resp.bytes()?.to_vec()) {
+                Ok(text) => (text, None),
+                Err(e) => (
+                    String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                    Some(format!(
+                        "(binary body of {} bytes, use --save or save_to to write it to a file)",
+                        e.as_bytes().len()
+                    )),
+                ),
+            }
         }
+    };
+
+    match (&not_printed, &ctx.opts.selector) {
+        (Some(note), _) => printer.response_without_body(status, &headers, note),
+        (None, None) => printer.response(status, &headers, &text, &ctx.opts.format),
+        (None, Some(selector)) => match selector.select(&text) {
+            Ok(Some(value)) => {
+                printer.response(status, &headers, &extract::to_var(value), &ctx.opts.format)
+            }
+            Ok(None) => printer.log(format!("nothing selected in `{}`", request.name)),
+            Err(e) => printer.log(format!("could not select in `{}`: {e:#}", request.name)),
+        },
     }
     vars.insert("response_body".to_string(), text.clone());
     vars.insert("response_status".to_string(), status.as_u16().to_string());
+    // extracted values are visible to the after-scripts
+    failures.extend(extract::extract(
+        db,
+        &api.name,
+        &request.extract,
+        &text,
+        vars,
+    )?);
 
+    let response = ScriptResponse::new(status.as_u16(), &headers, text);
+    failures.extend(run_scripts_after(
+        db, &api.name, &scripts, vars, &response, &env,
+    )?);
 
-    run_scripts_after(conn, &api.name, scripts, vars)?;
+    ctx.ran
+        .at
+        .lock()
+        .unwrap()
+        .insert(request.name.clone(), Instant::now());
 
-    Ok(())
+    let outcome = RequestOutcome {
+        name: request.name.clone(),
+        failures,
+        ..Default::default()
+    };
+    Ok((outcome, response, kept))
+}
+
+/// Send `request`, again after a retried status or network error. The
+/// last attempt's response or error is returned.
+fn send_with_retry(
+    client: &Client,
+    request: reqwest::blocking::Request,
+    auth: Option<&Auth>,
+    retry: Option<&Retry>,
+    limiter: &RateLimiter,
+    printer: &Printer,
+) -> anyhow::Result<Response> {
+    let Some(retry) = retry else {
+        limiter.wait();
+        return auth::send(client, request, auth, printer);
+    };
+
+    let mut attempt = 1;
+    loop {
+        // signatures and digests are computed again on every attempt
+        let sent = request
+            .try_clone()
+            .context("the request body can't be replayed")?;
+        limiter.wait();
+        let result = auth::send(client, sent, auth, printer);
+        if attempt >= retry.attempts() {
+            return result;
+        }
+
+        let (reason, wait) = match &result {
+            Ok(resp) if retry.retries_status(resp.status().as_u16()) => (
+                resp.status().to_string(),
+                retry_after(resp).unwrap_or_else(|| retry.delay(attempt)),
+            ),
+            Err(e) if retry.retries_network_errors() && is_network_error(e) => {
+                (format!("{e:#}"), retry.delay(attempt))
+            }
+            _ => return result,
+        };
+        printer.log(format!(
+            "Attempt {attempt}/{} failed ({reason}), retrying in {}ms",
+            retry.attempts(),
+            wait.as_millis()
+        ));
+        std::thread::sleep(wait);
+        attempt += 1;
+    }
+}
+
+/// Wait asked by a `Retry-After` header, in seconds or as an HTTP date.
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let wait = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            (at.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                .to_std()
+                .unwrap_or_default()
+        }
+    };
+    Some(wait.min(MAX_RETRY_AFTER))
+}
+
+/// No response was received: the connection failed or timed out.
+fn is_network_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_connect() || e.is_timeout() || e.is_request())
+    })
+}
+
+/// Stream the body of `resp` to a file, creating its directory. Returns
+/// the bytes written.
+fn save_body(body: &mut impl Read, path: &Path) -> anyhow::Result<u64> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = std::fs::File::create(path)?;
+    Ok(std::io::copy(body, &mut file)?)
+}
+
+/// Builder sending `prepared` as is.
+pub fn request_builder(client: &Client, prepared: ScriptRequest) -> anyhow::Result<RequestBuilder> {
+    let mut builder = client.request(prepared.method.parse()?, &prepared.url);
+
+    for (k, v) in &prepared.headers {
+        builder = builder.header(k.as_str(), v.to_string());
+    }
+
+    if !prepared.query.is_empty() {
+        let query: Vec<(String, String)> = prepared
+            .query
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        builder = builder.query(&query);
+    }
+
+    if !prepared.body.is_empty() {
+        builder = builder.body(prepared.body);
+    }
+
+    Ok(builder)
+}
+
+/// Render the request templates with `vars`.
+///
+/// Unless `strict`, headers that are not valid JSON yet (e.g. a placeholder
+/// only a before-script sets) are left out instead of failing.
+pub fn render_request(
+    api: &Api,
+    request: &Request,
+    vars: &Vars,
+    strict: bool,
+) -> anyhow::Result<ScriptRequest> {
+    let url = render_placeholders(&format!("{}{}", api.base_url, request.path), vars)?;
+
+    let mut headers = rhai::Map::new();
+    if let Some(h) = &request.headers {
+        let rendered = render_placeholders(h, vars)?;
+        match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&rendered) {
+            Ok(map) => {
+                for (k, v) in map {
+                    let value = v.as_str().map(str::to_string).unwrap_or(v.to_string());
+                    headers.insert(k.into(), value.into());
+                }
+            }
+            Err(e) if strict => return Err(e).context("invalid JSON in headers"),
+            Err(_) => {}
+        }
+    }
+
+    let body = match &request.body {
+        Some(b) => render_placeholders(b, vars)?,
+        None => String::new(),
+    };
+
+    Ok(ScriptRequest {
+        method: request.method.to_uppercase(),
+        url,
+        headers,
+        query: rhai::Map::new(),
+        body,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{Api, ProjectConfig, Request, Script};
+    use crate::config::{
+        Api, ApiScripts, Auth, Branch, HumanDuration, ParallelGroup, Persist, ProjectConfig,
+        RateLimit, Request, Script,
+    };
     use crate::storage::init_db;
     use crate::templating::Vars;
     use httpmock::prelude::*;
     use rusqlite::Connection;
+    use sha2::Digest;
     use std::collections::HashMap;
 
+    fn opts(format: &str) -> RunOptions {
+        RunOptions {
+            format: format.into(),
+            ..Default::default()
+        }
+    }
+
+    /// `GET /<name>`, with after-scripts
+    fn get(name: &str, scripts: &[&str]) -> Request {
+        Request {
+            name: name.into(),
+            method: "GET".into(),
+            path: format!("/{name}"),
+            scripts: scripts
+                .iter()
+                .map(|s| Script {
+                    before: false,
+                    script: s.to_string(),
+                    persist: Persist::All(false),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn run_single_request_get() {
         let server = MockServer::start();
@@ -131,6 +1102,7 @@ mod tests {
             name: "test".into(),
             base_url: server.base_url(),
             scenarios: HashMap::new(),
+            ..Default::default()
         };
 
         let req = Request {
@@ -140,18 +1112,20 @@ mod tests {
             headers: None,
             body: None,
             scripts: vec![],
+            ..Default::default()
         };
 
         let cfg = ProjectConfig {
             api,
             requests: vec![req],
+            ..Default::default()
         };
 
         let conn = Connection::open_in_memory().unwrap();
         init_db(&conn).unwrap();
 
         let vars = Vars::new();
-        run_route(&conn, &cfg, "docs", vars, "json").unwrap();
+        run_route(&conn, &cfg, "docs", vars, &opts("json")).unwrap();
 
         m.assert(); // ensure it was called
     }
@@ -177,6 +1151,7 @@ mod tests {
             name: "test".into(),
             base_url: server.base_url(),
             scenarios,
+            ..Default::default()
         };
 
         let r1 = Request {
@@ -186,6 +1161,7 @@ mod tests {
             headers: None,
             body: None,
             scripts: vec![],
+            ..Default::default()
         };
 
         let r2 = Request {
@@ -195,21 +1171,1200 @@ mod tests {
             headers: None,
             body: None,
             scripts: vec![],
+            ..Default::default()
         };
 
         let cfg = ProjectConfig {
             api,
             requests: vec![r1, r2],
+            ..Default::default()
         };
 
         let conn = Connection::open_in_memory().unwrap();
         init_db(&conn).unwrap();
 
         let vars = Vars::new();
-        run_route(&conn, &cfg, "scenario1", vars, "raw").unwrap();
+        run_route(&conn, &cfg, "scenario1", vars, &opts("raw")).unwrap();
 
         m1.assert();
         m2.assert();
     }
-}
 
+    #[test]
+    fn request_auth_overrides_api_auth() {
+        let server = MockServer::start();
+
+        let m = server.mock(|when, then| {
+            when.method(GET)
+                .path("/me")
+                .header("authorization", "Bearer request-token");
+            then.status(200);
+        });
+
+        let api = Api {
+            name: "test".into(),
+            base_url: server.base_url(),
+            auth: Some(Auth::Bearer {
+                token: "api-token".into(),
+            }),
+            ..Default::default()
+        };
+
+        let req = Request {
+            name: "me".into(),
+            method: "GET".into(),
+            path: "/me".into(),
+            auth: Some(Auth::Bearer {
+                token: "${token}".into(),
+            }),
+            ..Default::default()
+        };
+
+        let cfg = ProjectConfig {
+            api,
+            requests: vec![req],
+            ..Default::default()
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let mut vars = Vars::new();
+        vars.insert("token".into(), "request-token".into());
+        run_route(&conn, &cfg, "me", vars, &opts("raw")).unwrap();
+
+        m.assert();
+    }
+
+    #[test]
+    fn cookies_are_kept_across_scenario() {
+        let server = MockServer::start();
+
+        let login = server.mock(|when, then| {
+            when.method(POST).path("/login");
+            then.status(200).header("Set-Cookie", "session=abc; Path=/");
+        });
+        let me = server.mock(|when, then| {
+            when.method(GET).path("/me").cookie("session", "abc");
+            then.status(200);
+        });
+
+        let mut scenarios = HashMap::new();
+        scenarios.insert("flow".into(), vec!["login".into(), "me".into()]);
+
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "cookie_project".into(),
+                base_url: server.base_url(),
+                scenarios,
+                persist_cookies: true,
+                ..Default::default()
+            },
+            requests: vec![
+                Request {
+                    name: "login".into(),
+                    method: "POST".into(),
+                    path: "/login".into(),
+                    ..Default::default()
+                },
+                Request {
+                    name: "me".into(),
+                    method: "GET".into(),
+                    path: "/me".into(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        run_route(&conn, &cfg, "flow", Vars::new(), &opts("raw")).unwrap();
+
+        login.assert();
+        me.assert();
+        let stored = cookies::list(&conn, "cookie_project").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].name, "session");
+    }
+
+    #[test]
+    fn before_script_edits_sent_request() {
+        let server = MockServer::start();
+
+        let m = server.mock(|when, then| {
+            when.method(POST)
+                .path("/orders")
+                .query_param("dry_run", "true")
+                .header("authorization", "Bearer fresh")
+                .header("x-body-hash", hex::encode(sha2::Sha256::digest("{\"id\":1}")))
+                .body("{\"id\":1}");
+            then.status(201);
+        });
+
+        let api = Api {
+            name: "test".into(),
+            base_url: server.base_url(),
+            ..Default::default()
+        };
+
+        let req = Request {
+            name: "order".into(),
+            method: "POST".into(),
+            path: "/orders".into(),
+            headers: Some(r#"{"Authorization": "Bearer ${token}"}"#.into()),
+            body: Some(r#"{"id":1}"#.into()),
+            scripts: vec![Script {
+                before: true,
+                script: r#"
+                    request.headers["X-Body-Hash"] = sha256(request.body);
+                    request.query.dry_run = "true";
+                    return #{ token: "fresh" };
+                "#
+                .into(),
+                description: None,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let cfg = ProjectConfig {
+            api,
+            requests: vec![req],
+            ..Default::default()
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let mut vars = Vars::new();
+        vars.insert("token".into(), "stale".into());
+        run_route(&conn, &cfg, "order", vars, &opts("raw")).unwrap();
+
+        m.assert();
+    }
+
+    #[test]
+    fn failed_assertions_do_not_stop_scenario() {
+        let server = MockServer::start();
+
+        let m1 = server.mock(|when, then| {
+            when.method(GET).path("/a");
+            then.status(500);
+        });
+        let m2 = server.mock(|when, then| {
+            when.method(GET).path("/b");
+            then.status(200);
+        });
+
+        let mut scenarios = HashMap::new();
+        scenarios.insert("flow".into(), vec!["a".into(), "b".into()]);
+
+        let check_ok = Script {
+            before: false,
+            script: r#"assert(response.status == 200, "expected 200");"#.into(),
+            description: Some("status check".into()),
+            ..Default::default()
+        };
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                scenarios,
+                ..Default::default()
+            },
+            requests: vec![
+                Request {
+                    name: "a".into(),
+                    method: "GET".into(),
+                    path: "/a".into(),
+                    scripts: vec![check_ok.clone()],
+                    ..Default::default()
+                },
+                Request {
+                    name: "b".into(),
+                    method: "GET".into(),
+                    path: "/b".into(),
+                    scripts: vec![check_ok],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let report = run_route(&conn, &cfg, "flow", Vars::new(), &opts("raw")).unwrap();
+
+        m1.assert();
+        m2.assert();
+        assert_eq!(report.failure_count(), 1);
+        assert_eq!(report.requests[0].failures[0].script, "status check");
+        assert!(report.requests[1].failures.is_empty());
+        let err = report.ensure_passed(&Printer::default()).unwrap_err();
+        assert_eq!(err.to_string(), "1 assertion(s) failed");
+    }
+
+    #[test]
+    fn hooks_and_modules_run_around_every_request() {
+        let server = MockServer::start();
+
+        let m = server.mock(|when, then| {
+            when.method(GET).path("/me").header("x-trace", "trace-me");
+            then.status(200);
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("trace.rhai"),
+            r#"fn id(name) { "trace-" + name }"#,
+        )
+        .unwrap();
+
+        let api = Api {
+            name: "test".into(),
+            base_url: server.base_url(),
+            scripts: ApiScripts {
+                modules: vec![],
+                hooks: vec![
+                    Script {
+                        before: true,
+                        script: r#"
+                            import "trace" as trace;
+                            request.headers["X-Trace"] = trace::id("me");
+                        "#
+                        .into(),
+                        ..Default::default()
+                    },
+                    Script {
+                        before: false,
+                        script: r#"assert(response.status == 201, "hook check");"#.into(),
+                        description: Some("status hook".into()),
+                        ..Default::default()
+                    },
+                ],
+            },
+            ..Default::default()
+        };
+        let cfg = ProjectConfig {
+            api,
+            requests: vec![Request {
+                name: "me".into(),
+                method: "GET".into(),
+                path: "/me".into(),
+                ..Default::default()
+            }],
+            dir: Some(dir.path().to_path_buf()),
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let report = run_route(&conn, &cfg, "me", Vars::new(), &opts("raw")).unwrap();
+
+        m.assert();
+        assert_eq!(report.requests[0].failures[0].script, "status hook");
+    }
+
+    #[test]
+    fn scripts_can_make_requests() {
+        let server = MockServer::start();
+
+        let csrf = server.mock(|when, then| {
+            when.method(GET).path("/csrf");
+            then.status(200).body(r#"{"token":"t-1"}"#);
+        });
+        let audit = server.mock(|when, then| {
+            when.method(POST)
+                .path("/audit")
+                .header("content-type", "application/json")
+                .header("x-user", "bob")
+                .body(r#"{"step":"submit"}"#);
+            then.status(204);
+        });
+        let submit = server.mock(|when, then| {
+            when.method(POST).path("/submit").header("x-csrf", "t-1");
+            then.status(200);
+        });
+
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                ..Default::default()
+            },
+            requests: vec![
+                Request {
+                    name: "csrf".into(),
+                    method: "GET".into(),
+                    path: "/csrf".into(),
+                    ..Default::default()
+                },
+                Request {
+                    name: "submit".into(),
+                    method: "POST".into(),
+                    path: "/submit".into(),
+                    scripts: vec![Script {
+                        before: true,
+                        script: r#"
+                            request.headers["X-CSRF"] = run_request("csrf").json().token;
+                            let audit = http_post("/audit", #{ step: "submit" }, #{ "X-User": "${user}" });
+                            return #{ audit_status: audit.status };
+                        "#
+                        .into(),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                },
+            ],
+            dir: None,
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let vars = Vars::from([("user".to_string(), "bob".to_string())]);
+        let report = run_route(&conn, &cfg, "submit", vars, &opts("raw")).unwrap();
+
+        csrf.assert();
+        audit.assert();
+        submit.assert();
+        let names: Vec<_> = report.requests.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["csrf", "submit"]);
+    }
+
+    #[test]
+    fn run_request_nesting_is_bounded() {
+        let server = MockServer::start();
+
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                ..Default::default()
+            },
+            requests: vec![Request {
+                name: "again".into(),
+                method: "GET".into(),
+                path: "/".into(),
+                scripts: vec![Script {
+                    before: true,
+                    script: r#"run_request("again");"#.into(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            dir: None,
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let err = run_route(&conn, &cfg, "again", Vars::new(), &opts("raw")).unwrap_err();
+        assert!(format!("{err:#}").contains("nested more than 8 times"));
+    }
+
+    #[test]
+    fn conditional_steps_branch_on_responses() {
+        let server = MockServer::start();
+
+        let mock = |path: &str, status: u16| {
+            server.mock(|when, then| {
+                when.path(path.to_string());
+                then.status(status);
+            })
+        };
+        let lookup = mock("/lookup", 404);
+        let create = mock("/create", 201);
+        let welcome = mock("/welcome", 200);
+        let profile = mock("/profile", 200);
+        let after = mock("/after", 200);
+
+        let steps = vec![
+            Step::from("lookup"),
+            Step::Table(StepTable {
+                request: "create".into(),
+                condition: Some("response.status == 404".into()),
+                ..Default::default()
+            }),
+            Step::Table(StepTable {
+                request: "welcome".into(),
+                condition: Some("response.status == 200".into()),
+                ..Default::default()
+            }),
+            Step::Table(StepTable {
+                request: "profile".into(),
+                skip_unless: Some("user_id".into()),
+                ..Default::default()
+            }),
+            Step::Table(StepTable {
+                request: "broken".into(),
+                on_failure: Some(OnFailure::Continue),
+                ..Default::default()
+            }),
+            Step::from("after"),
+        ];
+
+        let mut broken = get("broken", &[]);
+        broken.scripts.push(Script {
+            before: true,
+            script: r#"throw "boom";"#.into(),
+            ..Default::default()
+        });
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                scenarios: HashMap::from([("signup".to_string(), steps)]),
+                ..Default::default()
+            },
+            requests: vec![
+                get("lookup", &[]),
+                get("create", &[]),
+                get("welcome", &[]),
+                get("profile", &[]),
+                broken,
+                get("after", &[]),
+            ],
+            dir: None,
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let report = run_route(&conn, &cfg, "signup", Vars::new(), &opts("raw")).unwrap();
+
+        lookup.assert();
+        create.assert();
+        welcome.assert_hits(0);
+        profile.assert_hits(0);
+        after.assert();
+        let names: Vec<_> = report.requests.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["lookup", "create", "broken", "after"]);
+        assert!(report.requests[2].error.as_ref().unwrap().contains("boom"));
+        let err = report.ensure_passed(&Printer::default()).unwrap_err();
+        assert_eq!(err.to_string(), "1 request(s) failed");
+    }
+
+    #[test]
+    fn on_failure_jumps_and_aborts() {
+        let server = MockServer::start();
+
+        let mock = |path: &str| {
+            server.mock(|when, then| {
+                when.path(path.to_string());
+                then.status(200);
+            })
+        };
+        let fix = mock("/fix");
+        let check = mock("/check");
+        let done = mock("/done");
+        let never = mock("/never");
+
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                scenarios: HashMap::from([(
+                    "repair".to_string(),
+                    vec![
+                        Step::Table(StepTable {
+                            request: "fix".into(),
+                            skip_unless: Some("needs_fix".into()),
+                            ..Default::default()
+                        }),
+                        Step::Table(StepTable {
+                            request: "check".into(),
+                            on_failure: Some(OnFailure::Goto("fix".into())),
+                            ..Default::default()
+                        }),
+                        Step::Table(StepTable {
+                            request: "done".into(),
+                            on_failure: Some(OnFailure::Abort),
+                            ..Default::default()
+                        }),
+                        Step::from("never"),
+                    ],
+                )]),
+                ..Default::default()
+            },
+            requests: vec![
+                get("fix", &[r#"return #{ fixed: "yes" };"#]),
+                get(
+                    "check",
+                    &[r#"
+                        assert(fixed == "yes", "not fixed");
+                        return #{ needs_fix: if fixed == "yes" { "" } else { "1" } };
+                    "#],
+                ),
+                get("done", &[r#"assert(false, "done fails");"#]),
+                get("never", &[]),
+            ],
+            dir: None,
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let vars = Vars::from([("fixed".to_string(), "no".to_string())]);
+        let report = run_route(&conn, &cfg, "repair", vars, &opts("raw")).unwrap();
+
+        fix.assert_hits(1);
+        check.assert_hits(2);
+        done.assert();
+        never.assert_hits(0);
+        let names: Vec<_> = report.requests.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["check", "fix", "check", "done"]);
+        assert_eq!(report.failure_count(), 2);
+    }
+
+    #[test]
+    fn steps_poll_and_loop() {
+        let server = MockServer::start();
+
+        let status = server.mock(|when, then| {
+            when.path("/status");
+            then.status(200);
+        });
+        let stuck = server.mock(|when, then| {
+            when.path("/stuck");
+            then.status(200);
+        });
+        let users: Vec<_> = ["a", "b", "me"]
+            .iter()
+            .map(|id| {
+                server.mock(|when, then| {
+                    when.path(format!("/users/{id}"));
+                    then.status(200);
+                })
+            })
+            .collect();
+
+        let interval = Some(HumanDuration(Duration::from_millis(1)));
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                scenarios: HashMap::from([(
+                    "job".to_string(),
+                    vec![
+                        Step::Table(StepTable {
+                            request: "status".into(),
+                            until: Some(r#"polls == "3""#.into()),
+                            interval,
+                            ..Default::default()
+                        }),
+                        Step::Table(StepTable {
+                            request: "stuck".into(),
+                            until: Some("false".into()),
+                            interval,
+                            max_attempts: Some(2),
+                            ..Default::default()
+                        }),
+                        Step::Table(StepTable {
+                            request: "user".into(),
+                            for_each: Some("user_ids".into()),
+                            item: Some("user_id".into()),
+                            ..Default::default()
+                        }),
+                        // back to the user_id from before the loop
+                        Step::from("user"),
+                    ],
+                )]),
+                ..Default::default()
+            },
+            requests: vec![
+                get(
+                    "status",
+                    &[r#"return #{ polls: parse_int(polls) + 1 };"#],
+                ),
+                get("stuck", &[]),
+                Request {
+                    path: "/users/${user_id}".into(),
+                    ..get("user", &[])
+                },
+            ],
+            dir: None,
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let vars = Vars::from([
+            ("polls".to_string(), "0".to_string()),
+            ("user_ids".to_string(), r#"["a","b"]"#.to_string()),
+            ("user_id".to_string(), "me".to_string()),
+        ]);
+        let report = run_route(&conn, &cfg, "job", vars, &opts("raw")).unwrap();
+
+        status.assert_hits(3);
+        stuck.assert_hits(2);
+        for user in users {
+            user.assert();
+        }
+        assert_eq!(report.requests.len(), 8);
+        assert_eq!(report.failure_count(), 1);
+        assert_eq!(report.requests[4].failures[0].script, "until");
+    }
+
+    #[test]
+    fn data_report_counts_failed_iterations() {
+        let failing = RunReport {
+            requests: vec![RequestOutcome {
+                name: "create".into(),
+                failures: vec![AssertionFailure {
+                    script: "check".into(),
+                    message: "status".into(),
+                }],
+                ..Default::default()
+            }],
+        };
+        let report = DataReport {
+            iterations: vec![
+                Ok(RunReport::default()),
+                Err(anyhow::anyhow!("boom")),
+                Ok(failing),
+            ],
+        };
+
+        let err = report.ensure_passed(&Printer::default()).unwrap_err();
+        assert_eq!(err.to_string(), "2 of 3 iteration(s) failed");
+        assert!(DataReport::default().ensure_passed(&Printer::default()).is_ok());
+    }
+
+    #[test]
+    fn parallel_branches_merge_their_variables() {
+        let server = MockServer::start();
+
+        let mocks: Vec<_> = ["/a", "/b", "/c", "/d/from-a/b-then-c"]
+            .iter()
+            .map(|path| {
+                server.mock(|when, then| {
+                    when.path(*path);
+                    then.status(200);
+                })
+            })
+            .collect();
+
+        let mut a = get("a", &[r#"return #{ from_a: "from-a", last: "a" };"#]);
+        a.scripts[0].persist = Persist::Only(vec!["from_a".into()]);
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                scenarios: HashMap::from([(
+                    "warmup".to_string(),
+                    vec![
+                        Step::Parallel(ParallelGroup {
+                            parallel: vec![
+                                Branch::Step(Box::new("a".into())),
+                                Branch::Steps(vec!["b".into(), "c".into()]),
+                            ],
+                            name: None,
+                        }),
+                        Step::from("d"),
+                    ],
+                )]),
+                ..Default::default()
+            },
+            requests: vec![
+                a,
+                get("b", &[r#"return #{ last: "b" };"#]),
+                get("c", &[r#"return #{ last: last + "-then-c" };"#]),
+                Request {
+                    path: "/d/${from_a}/${last}".into(),
+                    ..get("d", &[])
+                },
+            ],
+            dir: None,
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        for concurrency in [None, Some(1)] {
+            let opts = RunOptions {
+                concurrency,
+                ..opts("raw")
+            };
+            let report = run_route(&conn, &cfg, "warmup", Vars::new(), &opts).unwrap();
+
+            let names: Vec<_> = report.requests.iter().map(|r| r.name.as_str()).collect();
+            assert_eq!(names, vec!["a", "b", "c", "d"]);
+        }
+        for mock in mocks {
+            mock.assert_hits(2);
+        }
+        // the branches persisted their variables through the run's connection
+        let (_, saved) = storage::load_variables(&conn, "test").unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].value, "from-a");
+    }
+
+    #[test]
+    fn parallel_branches_share_needs_and_last_response() {
+        let server = MockServer::start();
+        let mock = |path: &str, status: u16| {
+            server.mock(|when, then| {
+                when.path(path.to_string());
+                then.status(status);
+            })
+        };
+        let (login, extra, d) = (mock("/login", 200), mock("/extra", 200), mock("/d", 200));
+        let others = [mock("/a", 200), mock("/b", 200), mock("/c", 201)];
+
+        let needing = |name: &str, needs: &[&str]| Request {
+            needs: needs.iter().map(|n| n.to_string()).collect(),
+            ..get(name, &[])
+        };
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                scenarios: HashMap::from([(
+                    "group".to_string(),
+                    vec![
+                        Step::from("a"),
+                        Step::Parallel(ParallelGroup {
+                            parallel: vec![
+                                Branch::Step(Box::new("b".into())),
+                                Branch::Step(Box::new("c".into())),
+                            ],
+                            name: None,
+                        }),
+                        // c is declared last, its response wins
+                        Step::Table(StepTable {
+                            request: "d".into(),
+                            condition: Some("response.status == 201".into()),
+                            ..Default::default()
+                        }),
+                    ],
+                )]),
+                ..Default::default()
+            },
+            requests: vec![
+                needing("login", &[]),
+                needing("extra", &[]),
+                needing("a", &["login"]),
+                needing("b", &["login", "extra"]),
+                needing("c", &["login", "extra"]),
+                needing("d", &[]),
+            ],
+            dir: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let report = run_route(&conn, &cfg, "group", Vars::new(), &opts("raw")).unwrap();
+
+        report.ensure_passed(&Printer::default()).unwrap();
+        login.assert_hits(1);
+        extra.assert_hits(1);
+        d.assert_hits(1);
+        for mock in others {
+            mock.assert_hits(1);
+        }
+    }
+
+    #[test]
+    fn needs_run_first_unless_fresh() {
+        let server = MockServer::start();
+        let mock = |path: &str| {
+            server.mock(|when, then| {
+                when.path(path.to_string());
+                then.status(200);
+            })
+        };
+        let (login, user, base, top) = (mock("/login"), mock("/user"), mock("/base"), mock("/top"));
+
+        let needing = |name: &str, needs: &[&str]| Request {
+            needs: needs.iter().map(|n| n.to_string()).collect(),
+            ..get(name, &[])
+        };
+        let mut cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                ..Default::default()
+            },
+            requests: vec![
+                Request {
+                    provides: vec!["token".into()],
+                    ..get("login", &[r#"return #{ token: "t" };"#])
+                },
+                needing("user", &["login"]),
+                // diamond: base is needed twice, but runs once
+                needing("top", &["left", "right"]),
+                needing("left", &["base"]),
+                needing("right", &["base"]),
+                needing("base", &[]),
+                needing("loop_a", &["loop_b"]),
+                needing("loop_b", &["loop_a"]),
+                needing("orphan", &["missing"]),
+            ],
+            dir: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+        let token = || Vars::from([("token".to_string(), "t".to_string())]);
+
+        let report = run_route(&conn, &cfg, "user", Vars::new(), &opts("raw")).unwrap();
+        let names: Vec<_> = report.requests.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["login", "user"]);
+        login.assert_hits(1);
+        user.assert_hits(1);
+
+        // the token is set already
+        run_route(&conn, &cfg, "user", token(), &opts("raw")).unwrap();
+        login.assert_hits(1);
+
+        // with a TTL, it has to be saved recently
+        cfg.requests[0].fresh_for = Some(HumanDuration(Duration::from_secs(3600)));
+        run_route(&conn, &cfg, "user", token(), &opts("raw")).unwrap();
+        login.assert_hits(2);
+        storage::set_variable(&conn, "token", "t", Some("test")).unwrap();
+        run_route(&conn, &cfg, "user", token(), &opts("raw")).unwrap();
+        login.assert_hits(2);
+        conn.execute("UPDATE variables SET updated_at = updated_at - 7200", [])
+            .unwrap();
+        run_route(&conn, &cfg, "user", token(), &opts("raw")).unwrap();
+        login.assert_hits(3);
+
+        let report = run_route(&conn, &cfg, "top", Vars::new(), &opts("raw")).unwrap();
+        let names: Vec<_> = report.requests.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["base", "left", "right", "top"]);
+        base.assert_hits(1);
+        top.assert_hits(1);
+
+        let err = run_route(&conn, &cfg, "loop_a", Vars::new(), &opts("raw")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "requests need each other: loop_a -> loop_b -> loop_a"
+        );
+        let err = run_route(&conn, &cfg, "orphan", Vars::new(), &opts("raw")).unwrap_err();
+        assert_eq!(err.to_string(), "request `missing` needed by `orphan` not found");
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let server = MockServer::start();
+        let unavailable = server.mock(|when, then| {
+            when.path("/unavailable");
+            then.status(503).header("Retry-After", "1");
+        });
+        let missing = server.mock(|when, then| {
+            when.path("/missing");
+            then.status(404);
+        });
+
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                retry: Some(Retry {
+                    base_ms: Some(1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            requests: vec![
+                Request {
+                    retry: Some(Retry {
+                        attempts: Some(2),
+                        ..Default::default()
+                    }),
+                    ..get("unavailable", &[r#"assert(response.status == 503, "final status");"#])
+                },
+                get("missing", &[]),
+            ],
+            dir: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let start = Instant::now();
+        let report = run_route(&conn, &cfg, "unavailable", Vars::new(), &opts("raw")).unwrap();
+        unavailable.assert_hits(2);
+        assert!(start.elapsed() >= Duration::from_secs(1), "Retry-After is honored");
+        report.ensure_passed(&Printer::default()).unwrap();
+
+        run_route(&conn, &cfg, "missing", Vars::new(), &opts("raw")).unwrap();
+        missing.assert_hits(1);
+
+        // nothing listens there anymore
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let cfg = ProjectConfig {
+            api: Api {
+                base_url: format!("http://{addr}"),
+                ..cfg.api
+            },
+            ..cfg
+        };
+        let start = Instant::now();
+        let err = run_route(&conn, &cfg, "missing", Vars::new(), &opts("raw")).unwrap_err();
+        assert!(is_network_error(&err), "{err:#}");
+        // 1ms then 2ms between the three attempts
+        assert!(start.elapsed() >= Duration::from_millis(3));
+    }
+
+    #[test]
+    fn requests_follow_the_rate_limit_and_delays() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(GET);
+            then.status(200);
+        });
+
+        let steps = vec![
+            Step::from("a"),
+            Step::from("b"),
+            Step::Table(StepTable {
+                request: "a".into(),
+                delay_ms: Some(300),
+                ..Default::default()
+            }),
+        ];
+        let mut cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                scenarios: HashMap::from([("slow".to_string(), steps)]),
+                rate_limit: Some(RateLimit {
+                    requests: 10,
+                    period: Duration::from_secs(1),
+                }),
+                ..Default::default()
+            },
+            requests: vec![get("a", &[]), get("b", &[])],
+            dir: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        // runs sharing a limiter share the limit
+        let shared = RunOptions {
+            limiter: Some(Arc::new(RateLimiter::new(cfg.api.rate_limit))),
+            ..opts("raw")
+        };
+        let start = Instant::now();
+        for _ in 0..4 {
+            run_route(&conn, &cfg, "a", Vars::new(), &shared).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        cfg.api.rate_limit = None;
+        let start = Instant::now();
+        run_route(&conn, &cfg, "slow", Vars::new(), &opts("raw")).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        m.assert_hits(7);
+    }
+
+    #[test]
+    fn extracted_values_feed_the_next_requests() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/users");
+            then.status(201).body(r#"{"id": 42, "tags": ["a"]}"#);
+        });
+        let user = server.mock(|when, then| {
+            when.path("/users/42");
+            then.status(200);
+        });
+
+        let steps = vec![Step::from("create"), Step::from("show")];
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                scenarios: HashMap::from([("flow".to_string(), steps)]),
+                ..Default::default()
+            },
+            requests: vec![
+                Request {
+                    path: "/users".into(),
+                    extract: HashMap::from([
+                        ("user_id".to_string(), "$.id".to_string()),
+                        ("missing".to_string(), "$.nope".to_string()),
+                    ]),
+                    ..get("create", &[r#"assert(user_id == "42", "seen by scripts");"#])
+                },
+                Request {
+                    path: "/users/${user_id}".into(),
+                    ..get("show", &[])
+                },
+            ],
+            dir: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let opts = RunOptions {
+            selector: Some(Selector::JsonPath("$.tags[0]".into())),
+            ..opts("json")
+        };
+        let report = run_route(&conn, &cfg, "flow", Vars::new(), &opts).unwrap();
+
+        user.assert();
+        assert_eq!(report.failure_count(), 1);
+        assert_eq!(report.requests[0].failures[0].script, "extract `missing`");
+    }
+
+    #[test]
+    fn bodies_are_saved_byte_for_byte() {
+        let pdf: &[u8] = b"%PDF-1.7\n\x00\x9f\x92\x96\xff";
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/report");
+            then.status(200)
+                .header("Content-Type", "application/pdf")
+                .body(pdf);
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().display().to_string();
+        let mut cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                ..Default::default()
+            },
+            requests: vec![Request {
+                save_to: Some("${out}/reports/${id}.pdf".into()),
+                ..get(
+                    "report",
+                    &[r#"assert(response_file.ends_with("7.pdf") && response.text == "", "file");"#],
+                )
+            }],
+            dir: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+        let vars = Vars::from([
+            ("out".to_string(), out.clone()),
+            ("id".to_string(), "7".to_string()),
+        ]);
+
+        let report = run_route(&conn, &cfg, "report", vars.clone(), &opts("json")).unwrap();
+        report.ensure_passed(&Printer::default()).unwrap();
+        let saved = dir.path().join("reports/7.pdf");
+        assert_eq!(std::fs::read(&saved).unwrap(), pdf);
+
+        // --save takes over save_to, and only writes once the run is over
+        cfg.requests[0].scripts.clear();
+        let save = RunOptions {
+            save: Some(format!("{out}/cli.pdf")),
+            ..opts("json")
+        };
+        std::fs::remove_file(&saved).unwrap();
+        run_route(&conn, &cfg, "report", vars.clone(), &save).unwrap();
+        assert_eq!(std::fs::read(dir.path().join("cli.pdf")).unwrap(), pdf);
+        assert!(!saved.exists());
+
+        // without a file, a binary body doesn't break the run
+        cfg.requests[0].save_to = None;
+        let report = run_route(&conn, &cfg, "report", vars, &opts("json")).unwrap();
+        report.ensure_passed(&Printer::default()).unwrap();
+    }
+
+    #[test]
+    fn save_option_only_takes_the_last_step() {
+        let server = MockServer::start();
+        let lookup = server.mock(|when, then| {
+            when.path("/lookup");
+            then.status(200).body(r#"{"id": 7}"#);
+        });
+        let report = server.mock(|when, then| {
+            when.path("/report/7");
+            then.status(200).body("%PDF");
+        });
+
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                scenarios: HashMap::from([(
+                    "download".to_string(),
+                    vec![Step::from("lookup"), Step::from("report")],
+                )]),
+                ..Default::default()
+            },
+            requests: vec![
+                Request {
+                    extract: HashMap::from([("id".to_string(), "$.id".to_string())]),
+                    ..get("lookup", &[])
+                },
+                Request {
+                    path: "/report/${id}".into(),
+                    ..get("report", &[])
+                },
+            ],
+            dir: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.pdf");
+        let save = RunOptions {
+            save: Some(path.display().to_string()),
+            ..opts("raw")
+        };
+        let run = run_route(&conn, &cfg, "download", Vars::new(), &save).unwrap();
+
+        run.ensure_passed(&Printer::default()).unwrap();
+        lookup.assert();
+        report.assert();
+        assert_eq!(std::fs::read(&path).unwrap(), b"%PDF");
+    }
+
+    #[test]
+    fn save_option_needs_a_response_from_the_last_step() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/a");
+            then.status(200).body("a");
+        });
+
+        let skipped = Step::Table(StepTable {
+            request: "a".into(),
+            skip_unless: Some("missing".into()),
+            ..Default::default()
+        });
+        let parallel = Step::Parallel(ParallelGroup {
+            parallel: vec![Branch::Step(Box::new("a".into()))],
+            name: None,
+        });
+        let cfg = ProjectConfig {
+            api: Api {
+                name: "test".into(),
+                base_url: server.base_url(),
+                scenarios: HashMap::from([
+                    ("skipped".to_string(), vec![Step::from("a"), skipped]),
+                    ("parallel".to_string(), vec![Step::from("a"), parallel]),
+                ]),
+                ..Default::default()
+            },
+            requests: vec![get("a", &[])],
+            dir: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.txt");
+        let save = RunOptions {
+            save: Some(path.display().to_string()),
+            ..opts("raw")
+        };
+        for scenario in ["skipped", "parallel"] {
+            let err = run_route(&conn, &cfg, scenario, Vars::new(), &save).unwrap_err();
+            assert!(err.to_string().contains("`--save` has nothing to save"), "{err:#}");
+        }
+        assert!(!path.exists());
+    }
+}
@@ -1,71 +1,664 @@
+use crate::signing::mac;
+use crate::output::Printer;
+use crate::storage::VariableStore;
 use crate::templating::Vars;
-use crate::storage::set_variable;
 use anyhow::Context;
-use rhai::{Dynamic, Engine, Map};
-use rusqlite::Connection;
+use base64::Engine as _;
+use rhai::module_resolvers::{FileModuleResolver, ModuleResolversCollection, StaticModuleResolver};
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Module};
+use sha2::Digest;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Operations a script may run before being stopped, so that a runaway
+/// loop fails instead of hanging the run.
+const MAX_OPERATIONS: u64 = 10_000_000;
+/// Largest string a script may hold, response bodies included.
+const MAX_STRING_SIZE: usize = 64 * 1024 * 1024;
+/// Largest array or object map a script may hold.
+const MAX_COLLECTION_SIZE: usize = 1_000_000;
+/// Wall-clock time a single script may run.
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Failed `assert()` / `expect()` call of a script.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssertionFailure {
+    /// Script description, or its position when it has none
+    pub script: String,
+    pub message: String,
+}
+
+/// Rhai modules importable by the scripts of a project.
+#[derive(Debug, Clone, Default)]
+pub struct Modules {
+    /// Modules declared in `[api.scripts]`, by file stem
+    declared: StaticModuleResolver,
+    /// Project directory, for the `.rhai` files next to the project
+    dir: Option<PathBuf>,
+}
+
+impl Modules {
+    /// Compile the declared module files, relative to `dir`.
+    pub fn load(dir: Option<&Path>, files: &[String]) -> anyhow::Result<Self> {
+        let engine = engine();
+        let mut declared = StaticModuleResolver::new();
+
+        for file in files {
+            let path = match dir {
+                Some(dir) => dir.join(file),
+                None => PathBuf::from(file),
+            };
+            let name = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .ok_or_else(|| anyhow::anyhow!("invalid script module path `{file}`"))?;
+            let ast = engine
+                .compile_file(path.clone())
+                .map_err(|e| anyhow::anyhow!("script module `{file}`: {e}"))?;
+            let module = Module::eval_ast_as_new(rhai::Scope::new(), &ast, &engine)
+                .map_err(|e| anyhow::anyhow!("script module `{file}`: {e}"))?;
+            declared.insert(name, module);
+        }
+
+        Ok(Self {
+            declared,
+            dir: dir.map(Path::to_path_buf),
+        })
+    }
+
+    fn install(&self, engine: &mut Engine) {
+        let mut resolvers = ModuleResolversCollection::new();
+        resolvers.push(self.declared.clone());
+        if let Some(dir) = &self.dir {
+            resolvers.push(FileModuleResolver::new_with_path(dir));
+        }
+        engine.set_module_resolver(resolvers);
+    }
+}
+
+/// Sends the requests scripts make with `http_get`, `http_post` and
+/// `run_request`.
+pub trait ScriptHost {
+    /// Send a request built by a script, its placeholders rendered with `vars`.
+    fn send(&self, request: ScriptRequest, vars: &Vars) -> anyhow::Result<ScriptResponse>;
+    /// Run a request of the project, with a copy of `vars`.
+    fn run_request(&self, name: &str, vars: &Vars) -> anyhow::Result<ScriptResponse>;
+}
+
+/// What scripts can reach besides their variables.
+#[derive(Clone, Default)]
+pub struct ScriptEnv {
+    pub modules: Modules,
+    /// Without a host, scripts can't make requests
+    pub host: Option<Rc<dyn ScriptHost>>,
+    /// Where descriptions and `print` go
+    pub printer: Printer,
+}
+
+/// Messages of the assertions failed by the running script.
+type Failures = Rc<RefCell<Vec<String>>>;
+
+/// Value wrapped by `expect(value)`.
+#[derive(Debug, Clone)]
+struct Expectation {
+    value: Dynamic,
+}
+
+/// Response exposed to after-scripts as `response`.
+#[derive(Debug, Clone)]
+pub struct ScriptResponse {
+    pub status: i64,
+    /// Lowercase names, repeated headers joined with ", "
+    pub headers: Map,
+    pub text: String,
+}
+
+impl ScriptResponse {
+    pub fn new(status: u16, headers: &reqwest::header::HeaderMap, text: String) -> Self {
+        let mut map = Map::new();
+        for (k, v) in headers.iter() {
+            let value = String::from_utf8_lossy(v.as_bytes()).into_owned();
+            map.entry(k.as_str().into())
+                .and_modify(|existing| *existing = format!("{existing}, {value}").into())
+                .or_insert_with(|| value.into());
+        }
+        Self {
+            status: status.into(),
+            headers: map,
+            text,
+        }
+    }
+
+    fn json(&mut self) -> Result<Dynamic, Box<EvalAltResult>> {
+        parse_json(&self.text)
+    }
+}
+
+/// Request exposed to before-scripts as `request`, sent as the scripts
+/// leave it.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Map,
+    /// Extra query parameters appended to `url`
+    pub query: Map,
+    /// Empty means no body
+    pub body: String,
+}
+
+impl ScriptRequest {
+    /// Keep what the scripts changed from `initial`, and take everything
+    /// they left untouched from `rerendered` (the request rendered again
+    /// with the variables they returned).
+    pub fn merge_untouched(self, initial: &ScriptRequest, rerendered: ScriptRequest) -> Self {
+        fn pick<T: PartialEq>(current: T, initial: &T, rerendered: T) -> T {
+            if &current == initial {
+                rerendered
+            } else {
+                current
+            }
+        }
+
+        let mut headers = self.headers.clone();
+        for (k, v) in rerendered.headers {
+            let untouched = match (self.headers.get(&k), initial.headers.get(&k)) {
+                (Some(current), Some(before)) => current.to_string() == before.to_string(),
+                // removed by a script
+                (None, Some(_)) => false,
+                (_, None) => true,
+            };
+            if untouched {
+                headers.insert(k, v);
+            }
+        }
+
+        Self {
+            method: pick(self.method, &initial.method, rerendered.method),
+            url: pick(self.url, &initial.url, rerendered.url),
+            headers,
+            query: self.query,
+            body: pick(self.body, &initial.body, rerendered.body),
+        }
+    }
+}
 
 pub fn run_scripts_before(
-    conn: &Connection,
+    store: &dyn VariableStore,
     project: &str,
     scripts: &[crate::config::Script],
     vars: &mut Vars,
-) -> anyhow::Result<()> {
-    run_scripts(conn, project, scripts.iter().filter(|s| s.before), vars)
+    request: &mut ScriptRequest,
+    env: &ScriptEnv,
+) -> anyhow::Result<Vec<AssertionFailure>> {
+    run_scripts(
+        store,
+        project,
+        scripts.iter().enumerate().filter(|(_, s)| s.before),
+        vars,
+        Some(request),
+        None,
+        env,
+    )
 }
 
 pub fn run_scripts_after(
-    conn: &Connection,
+    store: &dyn VariableStore,
     project: &str,
     scripts: &[crate::config::Script],
     vars: &mut Vars,
-) -> anyhow::Result<()> {
-    run_scripts(conn, project, scripts.iter().filter(|s| !s.before), vars)
+    response: &ScriptResponse,
+    env: &ScriptEnv,
+) -> anyhow::Result<Vec<AssertionFailure>> {
+    run_scripts(
+        store,
+        project,
+        scripts.iter().enumerate().filter(|(_, s)| !s.before),
+        vars,
+        None,
+        Some(response),
+        env,
+    )
+}
+
+/// Rhai engine with the qwest API registered:
+///
+/// - `request.method`, `request.url`, `request.headers`, `request.query`,
+///   `request.body`, all writable
+/// - `response.status`, `response.headers`, `response.text`, `response.json()`
+/// - `parse_json(text)`, `to_json(value)`
+/// - `base64(text)`, `base64_decode(text)`
+/// - `sha256(text)`, `hmac(key, data)`, `hmac("sha1" | "sha256" | "sha512", key, data)`
+///   (hex encoded)
+/// - `uuid()`, `now()` (unix timestamp in seconds)
+///
+/// Scripts are limited in operations, string and collection sizes, and
+/// stopped after `SCRIPT_TIMEOUT`.
+pub fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_COLLECTION_SIZE)
+        .set_max_map_size(MAX_COLLECTION_SIZE);
+    start_deadline(&mut engine, SCRIPT_TIMEOUT);
+
+    engine
+        .register_type_with_name::<ScriptRequest>("Request")
+        .register_get_set(
+            "method",
+            |r: &mut ScriptRequest| r.method.clone(),
+            |r: &mut ScriptRequest, v: String| r.method = v.to_uppercase(),
+        )
+        .register_get_set(
+            "url",
+            |r: &mut ScriptRequest| r.url.clone(),
+            |r: &mut ScriptRequest, v: String| r.url = v,
+        )
+        .register_get_set(
+            "headers",
+            |r: &mut ScriptRequest| r.headers.clone(),
+            |r: &mut ScriptRequest, v: Map| r.headers = v,
+        )
+        .register_get_set(
+            "query",
+            |r: &mut ScriptRequest| r.query.clone(),
+            |r: &mut ScriptRequest, v: Map| r.query = v,
+        )
+        .register_get_set(
+            "body",
+            |r: &mut ScriptRequest| r.body.clone(),
+            |r: &mut ScriptRequest, v: String| r.body = v,
+        );
+
+    engine
+        .register_type_with_name::<ScriptResponse>("Response")
+        .register_get("status", |r: &mut ScriptResponse| r.status)
+        .register_get("headers", |r: &mut ScriptResponse| r.headers.clone())
+        .register_get("text", |r: &mut ScriptResponse| r.text.clone())
+        .register_fn("json", ScriptResponse::json);
+
+    engine
+        .register_fn("parse_json", |text: &str| parse_json(text))
+        .register_fn(
+            "to_json",
+            |value: Dynamic| -> Result<String, Box<EvalAltResult>> {
+                serde_json::to_string(&value).map_err(|e| e.to_string().into())
+            },
+        )
+        .register_fn("base64", |text: &str| {
+            base64::engine::general_purpose::STANDARD.encode(text)
+        })
+        .register_fn(
+            "base64_decode",
+            |text: &str| -> Result<String, Box<EvalAltResult>> {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(text)
+                    .map_err(|e| e.to_string())?;
+                String::from_utf8(bytes).map_err(|e| e.to_string().into())
+            },
+        )
+        .register_fn("sha256", |text: &str| {
+            hex::encode(sha2::Sha256::digest(text))
+        })
+        .register_fn("hmac", |key: &str, data: &str| {
+            hex::encode(mac::<sha2::Sha256>(key.as_bytes(), data.as_bytes()))
+        })
+        .register_fn(
+            "hmac",
+            |algorithm: &str, key: &str, data: &str| -> Result<String, Box<EvalAltResult>> {
+                let (key, data) = (key.as_bytes(), data.as_bytes());
+                let raw = match algorithm {
+                    "sha1" => mac::<sha1::Sha1>(key, data),
+                    "sha256" => mac::<sha2::Sha256>(key, data),
+                    "sha512" => mac::<sha2::Sha512>(key, data),
+                    other => return Err(format!("unknown hmac algorithm `{other}`").into()),
+                };
+                Ok(hex::encode(raw))
+            },
+        )
+        .register_fn("uuid", || uuid::Uuid::new_v4().to_string())
+        .register_fn("now", || chrono::Utc::now().timestamp());
+
+    engine
+}
+
+/// Register `assert(cond[, msg])` and `expect(value).to_equal(other)`-style
+/// checks. Failures are pushed to `failures` instead of stopping the script,
+/// and every check returns whether it passed.
+fn register_assertions(engine: &mut Engine, failures: &Failures) {
+    let sink = failures.clone();
+    engine.register_fn("assert", move |cond: bool, msg: &str| {
+        if !cond {
+            sink.borrow_mut().push(msg.to_string());
+        }
+        cond
+    });
+    let sink = failures.clone();
+    engine.register_fn("assert", move |cond: bool| {
+        if !cond {
+            sink.borrow_mut().push("assertion failed".into());
+        }
+        cond
+    });
+
+    engine
+        .register_type_with_name::<Expectation>("Expectation")
+        .register_fn("expect", |value: Dynamic| Expectation { value });
+
+    let check =
+        |engine: &mut Engine, name: &str, verb: &'static str, f: fn(&Dynamic, &Dynamic) -> bool| {
+            let sink = failures.clone();
+            engine.register_fn(name, move |e: &mut Expectation, other: Dynamic| {
+                let passed = f(&e.value, &other);
+                if !passed {
+                    sink.borrow_mut()
+                        .push(format!("expected `{}` {verb} `{other}`", e.value));
+                }
+                passed
+            });
+        };
+    check(engine, "to_equal", "to equal", values_equal);
+    check(engine, "not_to_equal", "not to equal", |a, b| {
+        !values_equal(a, b)
+    });
+    check(engine, "to_contain", "to contain", contains);
+    check(
+        engine,
+        "to_be_greater_than",
+        "to be greater than",
+        |a, b| matches!((as_number(a), as_number(b)), (Some(a), Some(b)) if a > b),
+    );
+    check(
+        engine,
+        "to_be_less_than",
+        "to be less than",
+        |a, b| matches!((as_number(a), as_number(b)), (Some(a), Some(b)) if a < b),
+    );
+}
+
+/// Register `http_get(url[, headers])`, `http_post(url, body[, headers])`
+/// and `run_request(name)`, all returning a `Response`. Non-string bodies
+/// are sent as JSON.
+fn register_http(engine: &mut Engine, host: &Rc<dyn ScriptHost>, vars: &Rc<RefCell<Vars>>) {
+    type Sent = Result<ScriptResponse, Box<EvalAltResult>>;
+
+    let (h, v) = (host.clone(), vars.clone());
+    let send = move |method: &str, url: String, body: Dynamic, headers: Map| {
+        let mut request = ScriptRequest {
+            method: method.into(),
+            url,
+            headers,
+            ..Default::default()
+        };
+        if body.is_string() {
+            request.body = body.cast::<rhai::ImmutableString>().into();
+        } else if !body.is_unit() {
+            request.body = serde_json::to_string(&body).map_err(|e| e.to_string())?;
+            if !request.headers.keys().any(|k| k.eq_ignore_ascii_case("content-type")) {
+                request
+                    .headers
+                    .insert("Content-Type".into(), "application/json".into());
+            }
+        }
+        h.send(request, &v.borrow()).map_err(|e| format!("{e:#}").into())
+    };
+    let send = Rc::new(send);
+    let f = send.clone();
+    engine.register_fn("http_get", move |url: String| -> Sent {
+        f("GET", url, Dynamic::UNIT, Map::new())
+    });
+    let f = send.clone();
+    engine.register_fn("http_get", move |url: String, headers: Map| -> Sent {
+        f("GET", url, Dynamic::UNIT, headers)
+    });
+    let f = send.clone();
+    engine.register_fn("http_post", move |url: String, body: Dynamic| -> Sent {
+        f("POST", url, body, Map::new())
+    });
+    let f = send;
+    engine.register_fn(
+        "http_post",
+        move |url: String, body: Dynamic, headers: Map| -> Sent { f("POST", url, body, headers) },
+    );
+
+    let (h, v) = (host.clone(), vars.clone());
+    engine.register_fn("run_request", move |name: &str| -> Sent {
+        h.run_request(name, &v.borrow())
+            .map_err(|e| format!("{e:#}").into())
+    });
+}
+
+fn as_number(value: &Dynamic) -> Option<f64> {
+    value
+        .as_int()
+        .map(|i| i as f64)
+        .ok()
+        .or_else(|| value.as_float().ok())
+}
+
+/// Numbers compare by value (`1 == 1.0`), everything else by type and content.
+fn values_equal(a: &Dynamic, b: &Dynamic) -> bool {
+    match (as_number(a), as_number(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.type_name() == b.type_name() && a.to_string() == b.to_string(),
+    }
+}
+
+/// Substring of a string, item of an array, or key of a map.
+fn contains(container: &Dynamic, item: &Dynamic) -> bool {
+    if let Some(text) = container.read_lock::<rhai::ImmutableString>() {
+        return text.contains(item.to_string().as_str());
+    }
+    if let Some(array) = container.read_lock::<rhai::Array>() {
+        return array.iter().any(|v| values_equal(v, item));
+    }
+    if let Some(map) = container.read_lock::<Map>() {
+        return map.contains_key(item.to_string().as_str());
+    }
+    false
+}
+
+/// Evaluate the `if` of a scenario step, with the variables and the
+/// previous `response` in scope.
+pub fn eval_condition(
+    expr: &str,
+    vars: &Vars,
+    response: Option<&ScriptResponse>,
+) -> anyhow::Result<bool> {
+    engine()
+        .eval_expression_with_scope::<bool>(&mut step_scope(vars, response), expr)
+        .map_err(|e| script_error(&format!("condition `{expr}`"), expr, *e))
+}
+
+/// Evaluate the `for_each` of a scenario step: an array, or a string
+/// holding a JSON array. Items that are not strings are given as JSON.
+pub fn eval_list(
+    expr: &str,
+    vars: &Vars,
+    response: Option<&ScriptResponse>,
+) -> anyhow::Result<Vec<String>> {
+    let value = engine()
+        .eval_expression_with_scope::<Dynamic>(&mut step_scope(vars, response), expr)
+        .map_err(|e| script_error(&format!("list `{expr}`"), expr, *e))?;
+    let value = match value.clone().try_cast::<rhai::ImmutableString>() {
+        Some(text) => parse_json(&text).unwrap_or(value),
+        None => value,
+    };
+    let items = value
+        .try_cast::<rhai::Array>()
+        .ok_or_else(|| anyhow::anyhow!("`{expr}` is not a list"))?;
+
+    items
+        .into_iter()
+        .map(|item| match item.clone().try_cast::<rhai::ImmutableString>() {
+            Some(text) => Ok(text.into()),
+            None if item.is_array() || item.is_map() => Ok(serde_json::to_string(&item)?),
+            None => Ok(item.to_string()),
+        })
+        .collect()
+}
+
+/// Scope of the expressions of a scenario step.
+fn step_scope(vars: &Vars, response: Option<&ScriptResponse>) -> rhai::Scope<'static> {
+    let mut scope = vars_scope(vars);
+    if let Some(response) = response {
+        scope.push("response", response.clone());
+    }
+    scope
+}
+
+fn vars_scope(vars: &Vars) -> rhai::Scope<'static> {
+    let mut scope = rhai::Scope::new();
+    for (k, v) in vars.iter() {
+        scope.push(k.as_str(), v.clone());
+    }
+    scope
+}
+
+/// Stop the scripts run by `engine` once `timeout` elapsed from now.
+fn start_deadline(engine: &mut Engine, timeout: Duration) {
+    let started = Instant::now();
+    engine.on_progress(move |operations| {
+        (operations % 1024 == 0 && started.elapsed() > timeout).then_some(Dynamic::UNIT)
+    });
+}
+
+/// Describe a script error with the source line it points to:
+///
+/// ```text
+/// script `login` failed at line 3, column 9: Variable not found: tokn
+///   3 |     let x = tokn;
+///     |             ^
+/// ```
+fn script_error(label: &str, source: &str, mut err: EvalAltResult) -> anyhow::Error {
+    let position = err.take_position();
+    let message = match err {
+        EvalAltResult::ErrorTerminated(..) => {
+            format!("timed out after {}s", SCRIPT_TIMEOUT.as_secs())
+        }
+        EvalAltResult::ErrorTooManyOperations(..) => {
+            format!("stopped after {MAX_OPERATIONS} operations (endless loop?)")
+        }
+        other => other.to_string(),
+    };
+
+    let (Some(line), Some(column)) = (position.line(), position.position()) else {
+        return anyhow::anyhow!("{label} failed: {message}");
+    };
+    let text = source.lines().nth(line - 1).unwrap_or_default();
+    // keep tabs so that the caret lines up
+    let pad: String = text
+        .chars()
+        .take(column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let gutter = " ".repeat(line.to_string().len());
+    anyhow::anyhow!(
+        "{label} failed at line {line}, column {column}: {message}\n {line} | {text}\n {gutter} | {pad}^"
+    )
+}
+
+fn parse_json(text: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    rhai::serde::to_dynamic(value)
 }
 
 fn run_scripts<'a, I>(
-    conn: &Connection,
+    store: &dyn VariableStore,
     project: &str,
     scripts: I,
     vars: &mut Vars,
-) -> anyhow::Result<()>
+    mut request: Option<&mut ScriptRequest>,
+    response: Option<&ScriptResponse>,
+    env: &ScriptEnv,
+) -> anyhow::Result<Vec<AssertionFailure>>
 where
-    I: Iterator<Item = &'a crate::config::Script>,
+    // scripts with their position in the request, which numbers them
+    I: Iterator<Item = (usize, &'a crate::config::Script)>,
 {
-    let engine = Engine::new();
+    let mut engine = engine();
+    env.modules.install(&mut engine);
+    let printer = &env.printer;
+    let on_print = printer.clone();
+    engine.on_print(move |text| on_print.log(text));
+    let failures = Failures::default();
+    register_assertions(&mut engine, &failures);
+    // variables seen by the requests made by the running script
+    let snapshot = Rc::new(RefCell::new(Vars::new()));
+    if let Some(host) = &env.host {
+        register_http(&mut engine, host, &snapshot);
+    }
+    let mut report = Vec::new();
 
-    for script in scripts {
+    for (i, script) in scripts {
         if let Some(desc) = &script.description {
-            println!(">> {}", desc);
+            printer.log(format!(">> {}", desc));
         }
+        let label = match &script.description {
+            Some(desc) => desc.clone(),
+            None => format!("script #{}", i + 1),
+        };
 
-        let mut scope = rhai::Scope::new();
-        for (k, v) in vars.iter() {
-            scope.push(k.as_str(), v.clone());
+        snapshot.replace(vars.clone());
+        let mut scope = vars_scope(vars);
+        if let Some(request) = &request {
+            scope.push("request", (*request).clone());
+        }
+        if let Some(response) = response {
+            scope.push("response", response.clone());
         }
 
-        // 👉 Key change: map Rhai error to String, then into anyhow
+        start_deadline(&mut engine, SCRIPT_TIMEOUT);
         let result = engine
             .eval_with_scope::<Dynamic>(&mut scope, &script.script)
-            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+            .map_err(|e| {
+                let name = match &script.description {
+                    Some(desc) => format!("script `{desc}`"),
+                    None => label.clone(),
+                };
+                script_error(&name, &script.script, *e)
+            })?;
 
-        // If result is a map, convert keys to variables and persist them
+        if let Some(request) = request.as_deref_mut() {
+            if let Some(updated) = scope.get_value::<ScriptRequest>("request") {
+                *request = updated;
+            }
+        }
+
+        report.extend(
+            failures
+                .borrow_mut()
+                .drain(..)
+                .map(|message| AssertionFailure {
+                    script: label.clone(),
+                    message,
+                }),
+        );
+
+        // If result is a map, convert keys to variables and persist the ones asked for
         if let Some(map) = result.clone().try_cast::<Map>() {
             for (k, v) in map.into_iter() {
                 let val = v.to_string();
                 vars.insert(k.to_string(), val.clone());
-                set_variable(conn, &k, &val, Some(project))
-                    .with_context(|| format!("failed to persist script variable `{k}`"))?;
+                if script.persist.includes(&k) {
+                    store
+                        .set_variable(&k, &val, Some(project))
+                        .with_context(|| format!("failed to persist script variable `{k}`"))?;
+                }
             }
         }
     }
 
-    Ok(())
+    Ok(report)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::Script;
+    use crate::config::{Persist, Script};
     use crate::storage::{init_db, load_variables};
     use crate::templating::Vars;
     use rusqlite::Connection;
@@ -79,11 +672,21 @@ mod tests {
             before: true,
             script: r#"return #{ token: "abc123", answer: 42 };"#.into(),
             description: None,
+            ..Default::default()
         };
 
         let mut vars = Vars::new();
 
-        run_scripts_before(&conn, "my_project", &[script], &mut vars).unwrap();
+        let mut request = ScriptRequest::default();
+        run_scripts_before(
+            &conn,
+            "my_project",
+            &[script],
+            &mut vars,
+            &mut request,
+            &ScriptEnv::default(),
+        )
+        .unwrap();
 
         // in-memory vars
         assert_eq!(vars.get("token").unwrap(), "abc123");
@@ -108,13 +711,388 @@ mod tests {
             "#
             .into(),
             description: None,
+            ..Default::default()
         };
 
         let mut vars = Vars::new();
         vars.insert("name".into(), "Enzo".into());
 
-        run_scripts_before(&conn, "proj", &[script], &mut vars).unwrap();
+        let mut request = ScriptRequest::default();
+        run_scripts_before(
+            &conn,
+            "proj",
+            &[script],
+            &mut vars,
+            &mut request,
+            &ScriptEnv::default(),
+        )
+        .unwrap();
         assert_eq!(vars.get("greeting").unwrap(), "Hello, Enzo");
     }
-}
 
+    #[test]
+    fn after_script_reads_response_json() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let script = Script {
+            before: false,
+            script: r#"return #{ token: response.json().data.token, code: response.status };"#
+                .into(),
+            description: None,
+            ..Default::default()
+        };
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        let response = ScriptResponse::new(201, &headers, r#"{"data":{"token":"t-1"}}"#.into());
+
+        let mut vars = Vars::new();
+        run_scripts_after(
+            &conn,
+            "proj",
+            &[script],
+            &mut vars,
+            &response,
+            &ScriptEnv::default(),
+        )
+        .unwrap();
+
+        assert_eq!(vars.get("token").unwrap(), "t-1");
+        assert_eq!(vars.get("code").unwrap(), "201");
+    }
+
+    #[test]
+    fn engine_exposes_helpers() {
+        let engine = engine();
+
+        let json: String = engine
+            .eval(r#"to_json(parse_json(`{"a":[1,2]}`).a)"#)
+            .unwrap();
+        assert_eq!(json, "[1,2]");
+
+        let encoded: String = engine.eval(r#"base64("user:pass")"#).unwrap();
+        assert_eq!(encoded, "dXNlcjpwYXNz");
+        let decoded: String = engine.eval(r#"base64_decode("dXNlcjpwYXNz")"#).unwrap();
+        assert_eq!(decoded, "user:pass");
+
+        let digest: String = engine.eval(r#"sha256("abc")"#).unwrap();
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let signature: String = engine.eval(r#"hmac("key", "data")"#).unwrap();
+        assert_eq!(
+            signature,
+            engine
+                .eval::<String>(r#"hmac("sha256", "key", "data")"#)
+                .unwrap()
+        );
+        assert!(engine
+            .eval::<String>(r#"hmac("md5", "key", "data")"#)
+            .is_err());
+
+        let id: String = engine.eval("uuid()").unwrap();
+        assert_eq!(id.len(), 36);
+        assert!(engine.eval::<i64>("now()").unwrap() > 1_600_000_000);
+    }
+
+    #[test]
+    fn response_headers_are_a_map() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.append("set-cookie", "a=1".parse().unwrap());
+        headers.append("set-cookie", "b=2".parse().unwrap());
+        let response = ScriptResponse::new(200, &headers, String::new());
+
+        let mut scope = rhai::Scope::new();
+        scope.push("response", response);
+        let cookie: String = engine()
+            .eval_with_scope(&mut scope, r#"response.headers["set-cookie"]"#)
+            .unwrap();
+        assert_eq!(cookie, "a=1, b=2");
+    }
+
+    #[test]
+    fn before_script_can_mutate_request() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let script = Script {
+            before: true,
+            script: r#"
+                request.method = "put";
+                request.headers["X-Signature"] = hmac("key", request.body);
+                request.query.dry_run = "true";
+            "#
+            .into(),
+            description: None,
+            ..Default::default()
+        };
+
+        let mut request = ScriptRequest {
+            method: "POST".into(),
+            url: "https://example.com/orders".into(),
+            body: r#"{"id":1}"#.into(),
+            ..Default::default()
+        };
+        run_scripts_before(
+            &conn,
+            "proj",
+            &[script],
+            &mut Vars::new(),
+            &mut request,
+            &ScriptEnv::default(),
+        )
+        .unwrap();
+
+        assert_eq!(request.method, "PUT");
+        assert_eq!(
+            request.headers["X-Signature"].to_string(),
+            hex::encode(mac::<sha2::Sha256>(b"key", br#"{"id":1}"#))
+        );
+        assert_eq!(request.query["dry_run"].to_string(), "true");
+    }
+
+    #[test]
+    fn merge_untouched_takes_rerendered_fields() {
+        let initial = ScriptRequest {
+            method: "GET".into(),
+            url: "https://example.com/old-token".into(),
+            headers: Map::from_iter([
+                ("Authorization".into(), "Bearer old".into()),
+                ("X-Removed".into(), "1".into()),
+            ]),
+            ..Default::default()
+        };
+        let mut scripted = initial.clone();
+        scripted.body = "changed".into();
+        scripted.headers.remove("X-Removed");
+        scripted.headers.insert("X-Added".into(), "1".into());
+
+        let mut rerendered = initial.clone();
+        rerendered.url = "https://example.com/new-token".into();
+        rerendered
+            .headers
+            .insert("Authorization".into(), "Bearer new".into());
+
+        let merged = scripted.merge_untouched(&initial, rerendered);
+
+        assert_eq!(merged.url, "https://example.com/new-token");
+        assert_eq!(merged.body, "changed");
+        assert_eq!(merged.headers["Authorization"].to_string(), "Bearer new");
+        assert!(merged.headers.contains_key("X-Added"));
+        assert!(!merged.headers.contains_key("X-Removed"));
+    }
+
+    #[test]
+    fn failed_assertions_are_collected() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let scripts = [
+            Script {
+                before: true,
+                script: "42".into(),
+                ..Default::default()
+            },
+            Script {
+                before: false,
+                script: r#"
+                    assert(response.status == 200, "status should be 200");
+                    expect(response.json().items).to_contain(3);
+                    expect(response.json().count).to_equal(2.0);
+                    return #{ checked: true };
+                "#
+                .into(),
+                description: Some("check listing".into()),
+                ..Default::default()
+            },
+            Script {
+                before: false,
+                script: r#"expect(response.text).to_contain("missing");"#.into(),
+                description: None,
+                ..Default::default()
+            },
+        ];
+        let response = ScriptResponse::new(
+            500,
+            &reqwest::header::HeaderMap::new(),
+            r#"{"items":[1,2,3],"count":2}"#.into(),
+        );
+
+        let mut vars = Vars::new();
+        let failures = run_scripts_after(
+            &conn,
+            "proj",
+            &scripts,
+            &mut vars,
+            &response,
+            &ScriptEnv::default(),
+        )
+        .unwrap();
+
+        // the script kept running after the failed assert
+        assert_eq!(vars.get("checked").unwrap(), "true");
+        assert_eq!(
+            failures,
+            vec![
+                AssertionFailure {
+                    script: "check listing".into(),
+                    message: "status should be 200".into(),
+                },
+                AssertionFailure {
+                    // numbered among all the scripts of the request
+                    script: "script #3".into(),
+                    message: r#"expected `{"items":[1,2,3],"count":2}` to contain `missing`"#
+                        .into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn persist_controls_which_variables_reach_the_db() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let scripts = [
+            Script {
+                before: true,
+                script: r#"return #{ job_id: "j-1" };"#.into(),
+                persist: Persist::All(false),
+                ..Default::default()
+            },
+            Script {
+                before: true,
+                script: r#"return #{ token: "t", tmp: "x" };"#.into(),
+                persist: Persist::Only(vec!["token".into()]),
+                ..Default::default()
+            },
+        ];
+
+        let mut vars = Vars::new();
+        let mut request = ScriptRequest::default();
+        run_scripts_before(
+            &conn,
+            "proj",
+            &scripts,
+            &mut vars,
+            &mut request,
+            &ScriptEnv::default(),
+        )
+        .unwrap();
+
+        // all of them are available for the rest of the run
+        assert_eq!(vars.get("job_id").unwrap(), "j-1");
+        assert_eq!(vars.get("tmp").unwrap(), "x");
+
+        let (_global, proj) = load_variables(&conn, "proj").unwrap();
+        let labels: Vec<_> = proj.iter().map(|v| v.label.as_str()).collect();
+        assert_eq!(labels, vec!["token"]);
+    }
+
+    #[test]
+    fn scripts_can_import_declared_modules() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("scripts")).unwrap();
+        std::fs::write(
+            dir.path().join("scripts/auth.rhai"),
+            r#"fn header(token) { "Bearer " + token }"#,
+        )
+        .unwrap();
+        let modules = Modules::load(Some(dir.path()), &["scripts/auth.rhai".into()]).unwrap();
+
+        let script = Script {
+            before: true,
+            script: r#"
+                import "auth" as auth;
+                return #{ authorization: auth::header("abc") };
+            "#
+            .into(),
+            persist: Persist::All(false),
+            ..Default::default()
+        };
+
+        let mut vars = Vars::new();
+        let mut request = ScriptRequest::default();
+        let env = ScriptEnv {
+            modules,
+            ..Default::default()
+        };
+        run_scripts_before(&conn, "proj", &[script], &mut vars, &mut request, &env).unwrap();
+
+        assert_eq!(vars.get("authorization").unwrap(), "Bearer abc");
+    }
+
+    #[test]
+    fn script_errors_point_at_the_source() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let script = Script {
+            before: true,
+            script: "let a = 1;\n    let b = missing + a;\n".into(),
+            description: Some("compute".into()),
+            ..Default::default()
+        };
+
+        let err = run_scripts_before(
+            &conn,
+            "proj",
+            &[script],
+            &mut Vars::new(),
+            &mut ScriptRequest::default(),
+            &ScriptEnv::default(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "script `compute` failed at line 2, column 13: Variable not found: missing\n \
+             2 |     let b = missing + a;\n   |             ^"
+        );
+    }
+
+    #[test]
+    fn runaway_scripts_are_stopped() {
+        let err = engine().eval::<Dynamic>("loop {}").unwrap_err();
+        assert!(matches!(*err, EvalAltResult::ErrorTooManyOperations(..)));
+
+        let mut timed = engine();
+        timed.set_max_operations(0);
+        start_deadline(&mut timed, Duration::from_millis(50));
+        let err = timed.eval::<Dynamic>("loop {}").unwrap_err();
+        assert!(matches!(*err, EvalAltResult::ErrorTerminated(..)));
+
+        let err = engine()
+            .eval::<Dynamic>(r#"let s = "x"; loop { s += s; }"#)
+            .unwrap_err();
+        assert!(matches!(*err, EvalAltResult::ErrorDataTooLarge(..)));
+    }
+
+    #[test]
+    fn lists_are_arrays_or_json() {
+        let vars = Vars::from([
+            ("ids".to_string(), "[1, 2]".to_string()),
+            ("name".to_string(), "bob".to_string()),
+        ]);
+        let response = ScriptResponse::new(
+            200,
+            &reqwest::header::HeaderMap::new(),
+            r#"{"users":[{"id":"a"},{"id":"b"}]}"#.into(),
+        );
+
+        assert_eq!(eval_list("ids", &vars, None).unwrap(), vec!["1", "2"]);
+        assert_eq!(
+            eval_list(r#"["x", name]"#, &vars, None).unwrap(),
+            vec!["x", "bob"]
+        );
+        assert_eq!(
+            eval_list("response.json().users", &vars, Some(&response)).unwrap(),
+            vec![r#"{"id":"a"}"#, r#"{"id":"b"}"#]
+        );
+        assert!(eval_list("name", &vars, None).is_err());
+    }
+}
@@ -1,11 +1,19 @@
 use rusqlite::{params, Connection};
 use std::path::PathBuf;
+use std::sync::mpsc;
 
 #[derive(Debug, Clone)]
 pub struct Variable {
     pub label: String,
     pub value: String,
-    pub project: Option<String>,
+}
+
+/// OAuth2 token cached for a project, `expires_at` is a unix timestamp.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OAuthToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<i64>,
 }
 
 pub fn db_path() -> anyhow::Result<PathBuf> {
@@ -15,16 +23,134 @@ pub fn db_path() -> anyhow::Result<PathBuf> {
     Ok(base.join("qwest.sqlite"))
 }
 
+/// Query run by the thread owning the connection.
+type Job = Box<dyn FnOnce(&Connection) + Send>;
+
+/// Database as seen by a run. A connection can't leave its thread, so the
+/// threads of a run send their queries to the one owning it, see [`serve`].
+#[derive(Clone)]
+pub struct Db(mpsc::Sender<Job>);
+
+impl Db {
+    /// Run `query` with the connection, on the thread owning it.
+    pub fn call<T, F>(&self, query: F) -> T
+    where
+        F: FnOnce(&Connection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let job: Job = Box::new(move |conn| {
+            let _ = tx.send(query(conn));
+        });
+        self.0.send(job).expect("the database thread is gone");
+        rx.recv().expect("the database thread is gone")
+    }
+}
+
+/// Where scripts persist their variables.
+pub trait VariableStore {
+    fn set_variable(&self, label: &str, value: &str, project: Option<&str>)
+        -> rusqlite::Result<()>;
+}
+
+impl VariableStore for Connection {
+    fn set_variable(
+        &self,
+        label: &str,
+        value: &str,
+        project: Option<&str>,
+    ) -> rusqlite::Result<()> {
+        set_variable(self, label, value, project)
+    }
+}
+
+impl VariableStore for Db {
+    fn set_variable(
+        &self,
+        label: &str,
+        value: &str,
+        project: Option<&str>,
+    ) -> rusqlite::Result<()> {
+        let (label, value) = (label.to_string(), value.to_string());
+        let project = project.map(String::from);
+        self.call(move |conn| set_variable(conn, &label, &value, project.as_deref()))
+    }
+}
+
+/// Run `work` on a thread of its own, serving its queries with `conn`
+/// until it returns.
+pub fn serve<R, F>(conn: &Connection, work: F) -> R
+where
+    F: FnOnce(Db) -> R + Send,
+    R: Send,
+{
+    let (tx, jobs) = mpsc::channel::<Job>();
+    std::thread::scope(|scope| {
+        let worker = scope.spawn(move || work(Db(tx)));
+        // ends once `work` returned and dropped every copy of its `Db`
+        for job in jobs {
+            job(conn);
+        }
+        worker
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
 pub fn init_db(conn: &Connection) -> rusqlite::Result<()> {
     conn.execute_batch(
         r#"
         CREATE TABLE IF NOT EXISTS variables (
-            label   TEXT NOT NULL,
-            value   TEXT NOT NULL,
-            project TEXT NULL
+            label      TEXT NOT NULL,
+            value      TEXT NOT NULL,
+            project    TEXT NULL,
+            updated_at INTEGER NULL
+        );
+        CREATE TABLE IF NOT EXISTS oauth_tokens (
+            project       TEXT NOT NULL,
+            token_url     TEXT NOT NULL,
+            client_id     TEXT NOT NULL,
+            credentials   TEXT NOT NULL DEFAULT '',
+            access_token  TEXT NOT NULL,
+            refresh_token TEXT NULL,
+            expires_at    INTEGER NULL
+        );
+        CREATE TABLE IF NOT EXISTS cookies (
+            project     TEXT NOT NULL,
+            environment TEXT NULL,
+            jar         TEXT NOT NULL
         );
         "#,
-    )
+    )?;
+
+    // databases created before variables had a timestamp
+    add_column(conn, "variables", "updated_at", "INTEGER NULL")?;
+    // or before tokens were keyed by scopes and secret
+    add_column(
+        conn,
+        "oauth_tokens",
+        "credentials",
+        "TEXT NOT NULL DEFAULT ''",
+    )?;
+    Ok(())
+}
+
+fn add_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists(params![table, column])?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            [],
+        )?;
+    }
+    Ok(())
 }
 
 /// Insert or update variable.
@@ -43,8 +169,8 @@ pub fn set_variable(
     )?;
     conn.execute(
         r#"
-        INSERT INTO variables (label, value, project)
-        VALUES (?1, ?2, ?3);
+        INSERT INTO variables (label, value, project, updated_at)
+        VALUES (?1, ?2, ?3, unixepoch());
         "#,
         params![label, value, project],
     )?;
@@ -58,27 +184,25 @@ pub fn load_variables(
     project: &str,
 ) -> rusqlite::Result<(Vec<Variable>, Vec<Variable>)> {
     let mut stmt = conn.prepare(
-        "SELECT label, value, project FROM variables WHERE project IS NULL",
+        "SELECT label, value FROM variables WHERE project IS NULL",
     )?;
     let global = stmt
         .query_map([], |row| {
             Ok(Variable {
                 label: row.get(0)?,
                 value: row.get(1)?,
-                project: row.get(2)?,
             })
         })?
         .collect::<Result<Vec<_>, _>>()?;
 
     let mut stmt = conn.prepare(
-        "SELECT label, value, project FROM variables WHERE project = ?1",
+        "SELECT label, value FROM variables WHERE project = ?1",
     )?;
     let project_vars = stmt
         .query_map([project], |row| {
             Ok(Variable {
                 label: row.get(0)?,
                 value: row.get(1)?,
-                project: row.get(2)?,
             })
         })?
         .collect::<Result<Vec<_>, _>>()?;
@@ -86,6 +210,144 @@ pub fn load_variables(
     Ok((global, project_vars))
 }
 
+/// Unix timestamp of the last time a variable was set, the project one
+/// first. `None` if it is not set, or was set before timestamps were kept.
+pub fn variable_updated_at(
+    conn: &Connection,
+    label: &str,
+    project: &str,
+) -> rusqlite::Result<Option<i64>> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT updated_at FROM variables
+        WHERE label = ?1 AND (project = ?2 OR project IS NULL)
+        ORDER BY project IS NULL
+        LIMIT 1
+        "#,
+    )?;
+    let mut rows = stmt.query_map(params![label, project], |row| row.get(0))?;
+    Ok(rows.next().transpose()?.flatten())
+}
+
+/// Load the cached OAuth2 token of a project for a given token endpoint and
+/// client. `credentials` tells apart tokens of the same client obtained with
+/// other scopes or another secret.
+pub fn load_token(
+    conn: &Connection,
+    project: &str,
+    token_url: &str,
+    client_id: &str,
+    credentials: &str,
+) -> rusqlite::Result<Option<OAuthToken>> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT access_token, refresh_token, expires_at FROM oauth_tokens
+        WHERE project = ?1 AND token_url = ?2 AND client_id = ?3 AND credentials = ?4
+        "#,
+    )?;
+    let mut rows = stmt.query_map(params![project, token_url, client_id, credentials], |row| {
+        Ok(OAuthToken {
+            access_token: row.get(0)?,
+            refresh_token: row.get(1)?,
+            expires_at: row.get(2)?,
+        })
+    })?;
+    rows.next().transpose()
+}
+
+/// Insert or replace the cached OAuth2 token.
+pub fn save_token(
+    conn: &Connection,
+    project: &str,
+    token_url: &str,
+    client_id: &str,
+    credentials: &str,
+    token: &OAuthToken,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        r#"
+        DELETE FROM oauth_tokens
+        WHERE project = ?1 AND token_url = ?2 AND client_id = ?3;
+        "#,
+        params![project, token_url, client_id],
+    )?;
+    conn.execute(
+        r#"
+        INSERT INTO oauth_tokens
+            (project, token_url, client_id, credentials, access_token, refresh_token, expires_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);
+        "#,
+        params![
+            project,
+            token_url,
+            client_id,
+            credentials,
+            token.access_token,
+            token.refresh_token,
+            token.expires_at
+        ],
+    )?;
+    Ok(())
+}
+
+/// Load the serialized cookie jar of a project and environment.
+pub fn load_cookie_jar(
+    conn: &Connection,
+    project: &str,
+    environment: Option<&str>,
+) -> rusqlite::Result<Option<String>> {
+    let mut stmt =
+        conn.prepare("SELECT jar FROM cookies WHERE project = ?1 AND environment IS ?2")?;
+    let mut rows = stmt.query_map(params![project, environment], |row| row.get(0))?;
+    rows.next().transpose()
+}
+
+/// Load every cookie jar of a project, as (environment, jar).
+pub fn load_cookie_jars(
+    conn: &Connection,
+    project: &str,
+) -> rusqlite::Result<Vec<(Option<String>, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT environment, jar FROM cookies WHERE project = ?1 ORDER BY environment",
+    )?;
+    let jars = stmt
+        .query_map([project], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(jars)
+}
+
+/// Insert or replace the serialized cookie jar of a project and environment.
+pub fn save_cookie_jar(
+    conn: &Connection,
+    project: &str,
+    environment: Option<&str>,
+    jar: &str,
+) -> rusqlite::Result<()> {
+    delete_cookie_jar(conn, project, environment)?;
+    conn.execute(
+        "INSERT INTO cookies (project, environment, jar) VALUES (?1, ?2, ?3)",
+        params![project, environment, jar],
+    )?;
+    Ok(())
+}
+
+/// Delete the cookie jar of a project and environment.
+pub fn delete_cookie_jar(
+    conn: &Connection,
+    project: &str,
+    environment: Option<&str>,
+) -> rusqlite::Result<usize> {
+    conn.execute(
+        "DELETE FROM cookies WHERE project = ?1 AND environment IS ?2",
+        params![project, environment],
+    )
+}
+
+/// Delete the cookie jars of every environment of a project.
+pub fn delete_cookie_jars(conn: &Connection, project: &str) -> rusqlite::Result<usize> {
+    conn.execute("DELETE FROM cookies WHERE project = ?1", [project])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -107,12 +369,10 @@ mod tests {
         assert_eq!(global.len(), 1);
         assert_eq!(global[0].label, "token");
         assert_eq!(global[0].value, "global-token");
-        assert!(global[0].project.is_none());
 
         assert_eq!(project.len(), 2);
         let token = project.iter().find(|v| v.label == "token").unwrap();
         assert_eq!(token.value, "project-token");
-        assert_eq!(token.project.as_deref(), Some("my_project"));
 
         let url = project.iter().find(|v| v.label == "url").unwrap();
         assert_eq!(url.value, "https://example.com");
@@ -130,5 +390,90 @@ mod tests {
         assert_eq!(project.len(), 1);
         assert_eq!(project[0].value, "second");
     }
-}
 
+    #[test]
+    fn variables_keep_when_they_were_set() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        set_variable(&conn, "token", "global", None).unwrap();
+        conn.execute("UPDATE variables SET updated_at = 100", []).unwrap();
+        assert_eq!(variable_updated_at(&conn, "token", "project").unwrap(), Some(100));
+
+        set_variable(&conn, "token", "project", Some("project")).unwrap();
+        let updated_at = variable_updated_at(&conn, "token", "project").unwrap().unwrap();
+        assert!(updated_at > 100);
+        assert_eq!(variable_updated_at(&conn, "missing", "project").unwrap(), None);
+
+        // running it again on an up to date database is fine
+        init_db(&conn).unwrap();
+    }
+
+    #[test]
+    fn served_queries_run_on_the_connection() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let value = serve(&conn, |db| {
+            let other = db.clone();
+            std::thread::scope(|scope| {
+                scope.spawn(move || {
+                    other.call(|c| set_variable(c, "token", "t", Some("p")).unwrap())
+                });
+            });
+            db.call(|c| load_variables(c, "p").unwrap().1[0].value.clone())
+        });
+        assert_eq!(value, "t");
+    }
+
+    #[test]
+    fn save_and_load_token() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let url = "https://auth.example.com/token";
+        assert!(load_token(&conn, "project", url, "client", "").unwrap().is_none());
+
+        let first = OAuthToken {
+            access_token: "a1".into(),
+            refresh_token: Some("r1".into()),
+            expires_at: Some(100),
+        };
+        save_token(&conn, "project", url, "client", "", &first).unwrap();
+        let second = OAuthToken {
+            access_token: "a2".into(),
+            refresh_token: None,
+            expires_at: None,
+        };
+        save_token(&conn, "project", url, "client", "", &second).unwrap();
+
+        let loaded = load_token(&conn, "project", url, "client", "").unwrap();
+        assert_eq!(loaded, Some(second));
+        assert!(load_token(&conn, "other", url, "client", "").unwrap().is_none());
+    }
+
+    #[test]
+    fn cookie_jars_are_scoped_by_environment() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        save_cookie_jar(&conn, "p", None, "default").unwrap();
+        save_cookie_jar(&conn, "p", Some("staging.env"), "staging").unwrap();
+        save_cookie_jar(&conn, "p", Some("staging.env"), "staging-2").unwrap();
+
+        assert_eq!(
+            load_cookie_jar(&conn, "p", None).unwrap().as_deref(),
+            Some("default")
+        );
+        assert_eq!(
+            load_cookie_jar(&conn, "p", Some("staging.env")).unwrap().as_deref(),
+            Some("staging-2")
+        );
+        assert_eq!(load_cookie_jars(&conn, "p").unwrap().len(), 2);
+
+        delete_cookie_jar(&conn, "p", Some("staging.env")).unwrap();
+        assert_eq!(load_cookie_jars(&conn, "p").unwrap().len(), 1);
+        delete_cookie_jars(&conn, "p").unwrap();
+        assert!(load_cookie_jars(&conn, "p").unwrap().is_empty());
+    }
+}
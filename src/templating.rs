@@ -1,5 +1,4 @@
 use std::collections::HashMap;
-use anyhow::Context;
 use regex::Regex;
 
 pub type Vars = HashMap<String, String>;
@@ -9,6 +8,7 @@ pub fn merge_vars(
     global_vars: Vars,
     project_vars: Vars,
     cli_vars: Vars,
+    data_vars: Vars,
 ) -> Vars {
     let mut merged = Vars::new();
 
@@ -16,7 +16,8 @@ pub fn merge_vars(
     merged.extend(env_file_vars);
     merged.extend(global_vars);
     merged.extend(project_vars);
-    merged.extend(cli_vars); // highest precedence
+    merged.extend(cli_vars);
+    merged.extend(data_vars); // highest precedence, a row of `--data`
 
     merged
 }
@@ -53,14 +54,19 @@ mod tests {
         let mut cli = Vars::new();
         cli.insert("D".into(), "cli".into());
         cli.insert("E".into(), "cli".into());
+        cli.insert("F".into(), "cli".into());
 
-        let merged = merge_vars(env_file, global, project, cli);
+        let mut data = Vars::new();
+        data.insert("F".into(), "data".into());
+
+        let merged = merge_vars(env_file, global, project, cli, data);
 
         assert_eq!(merged.get("A").unwrap(), "env");
         assert_eq!(merged.get("B").unwrap(), "global");
         assert_eq!(merged.get("C").unwrap(), "project");
         assert_eq!(merged.get("D").unwrap(), "cli");
         assert_eq!(merged.get("E").unwrap(), "cli");
+        assert_eq!(merged.get("F").unwrap(), "data");
     }
 
     #[test]